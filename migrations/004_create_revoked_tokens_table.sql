@@ -0,0 +1,12 @@
+-- Create revoked_tokens table for access tokens invalidated before expiry
+CREATE TABLE revoked_tokens (
+    jti UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL, -- Rows can be purged once the token would have expired anyway
+    revoked_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_revoked_tokens_expires_at ON revoked_tokens(expires_at);
+
+-- Tokens issued at or before this instant are rejected ("log out everywhere")
+ALTER TABLE users ADD COLUMN tokens_valid_after TIMESTAMPTZ;
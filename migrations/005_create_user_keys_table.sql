@@ -0,0 +1,7 @@
+-- Create user_keys table holding each user's data-encryption key,
+-- wrapped by the master key. Deleting a row crypto-shreds that user's entries.
+CREATE TABLE user_keys (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    wrapped_key TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,15 @@
+-- Create reencryption_jobs table tracking master-key rotation progress
+CREATE TABLE reencryption_jobs (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    target_key_id VARCHAR(64) NOT NULL, -- Master key everything is being moved to
+    phase VARCHAR(32) NOT NULL, -- 'user_keys', then 'journal_entries'
+    last_id UUID, -- Keyset cursor within the current phase; resume point after a restart
+    total BIGINT NOT NULL DEFAULT 0,
+    processed BIGINT NOT NULL DEFAULT 0,
+    failed BIGINT NOT NULL DEFAULT 0,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_reencryption_jobs_target_key_id ON reencryption_jobs(target_key_id);
@@ -0,0 +1,15 @@
+-- Opt-in zero-knowledge mode: entries are encrypted on the client and the
+-- server only stores the ciphertext and key-derivation parameters verbatim.
+ALTER TABLE users ADD COLUMN client_encrypted BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Client-side KDF settings for the entry (salt, algorithm, cost parameters)
+ALTER TABLE journal_entries ADD COLUMN client_kdf JSONB;
+
+-- Client-wrapped key material for client-encrypted users. The server cannot unwrap it.
+CREATE TABLE client_key_material (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    wrapped_key TEXT NOT NULL,
+    kdf JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
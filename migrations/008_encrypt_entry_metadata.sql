@@ -0,0 +1,10 @@
+-- Titles and tags are encrypted at rest; ciphertexts no longer fit VARCHAR(255)
+ALTER TABLE journal_entries ALTER COLUMN title TYPE TEXT;
+
+-- Keyed HMAC blind indexes of the tags, so entries can be filtered by exact tag
+ALTER TABLE journal_entries ADD COLUMN tag_index TEXT[];
+
+-- FALSE for rows whose title and tags are still plaintext (legacy or client-encrypted)
+ALTER TABLE journal_entries ADD COLUMN metadata_encrypted BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX idx_journal_entries_tag_index ON journal_entries USING GIN (tag_index);
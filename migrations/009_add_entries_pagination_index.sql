@@ -0,0 +1,3 @@
+-- Keyset pagination walks a user's entries by (created_at, id), newest first
+CREATE INDEX idx_journal_entries_user_created_id ON journal_entries(user_id, created_at DESC, id DESC);
+CREATE INDEX idx_journal_entries_user_updated ON journal_entries(user_id, updated_at);
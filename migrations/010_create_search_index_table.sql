@@ -0,0 +1,14 @@
+-- Create search_index table: a per-user inverted index of keyed-hash tokens.
+-- Neither terms nor positions are stored in plaintext.
+CREATE TABLE search_index (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash VARCHAR(64) NOT NULL, -- Keyed HMAC of a term or term prefix
+    entry_id UUID NOT NULL REFERENCES journal_entries(id) ON DELETE CASCADE,
+    positions TEXT NOT NULL, -- Encrypted word positions of the token in the entry
+    PRIMARY KEY (user_id, token_hash, entry_id)
+);
+
+CREATE INDEX idx_search_index_entry_id ON search_index(entry_id);
+
+-- Entries written before search existed are indexed by the background search index job
+ALTER TABLE journal_entries ADD COLUMN search_indexed BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,16 @@
+-- Create journal_entry_revisions table: earlier versions of each entry,
+-- stored exactly as they were in journal_entries (still encrypted).
+CREATE TABLE journal_entry_revisions (
+    entry_id UUID NOT NULL REFERENCES journal_entries(id) ON DELETE CASCADE,
+    revision INTEGER NOT NULL, -- 1 is the entry as first created
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    title TEXT NOT NULL,
+    content TEXT NOT NULL,
+    mood_score INTEGER,
+    tags TEXT[],
+    client_kdf JSONB,
+    metadata_encrypted BOOLEAN NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL, -- When this version was written
+    replaced_at TIMESTAMPTZ NOT NULL, -- When it was overwritten
+    PRIMARY KEY (entry_id, revision)
+);
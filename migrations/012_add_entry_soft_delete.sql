@@ -0,0 +1,4 @@
+-- Soft delete: trashed entries keep their data until the purger removes them
+ALTER TABLE journal_entries ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX idx_journal_entries_deleted_at ON journal_entries(deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,5 @@
+-- Entry version for optimistic concurrency; bumped on every change and exposed as the ETag
+ALTER TABLE journal_entries ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
+
+-- Version of the entry each revision was taken from
+ALTER TABLE journal_entry_revisions ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
@@ -0,0 +1,42 @@
+-- Per-user change sequence for the sync feed. users.change_seq is the last
+-- number handed out; each entry records the number of its latest change.
+ALTER TABLE users ADD COLUMN change_seq BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE journal_entries ADD COLUMN change_seq BIGINT NOT NULL DEFAULT 0;
+
+-- Number existing entries in the order they were last changed
+UPDATE journal_entries e SET change_seq = numbered.seq
+FROM (
+    SELECT id, ROW_NUMBER() OVER (PARTITION BY user_id ORDER BY updated_at, id) AS seq
+    FROM journal_entries
+) numbered
+WHERE e.id = numbered.id;
+
+UPDATE users u SET change_seq = (
+    SELECT COALESCE(MAX(change_seq), 0) FROM journal_entries WHERE user_id = u.id
+);
+
+CREATE INDEX idx_journal_entries_user_change_seq ON journal_entries(user_id, change_seq);
+
+-- Entries deleted for good, so clients still learn about deletions they missed
+CREATE TABLE entry_tombstones (
+    entry_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    change_seq BIGINT NOT NULL, -- Sequence number of the deletion
+    deleted_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idx_entry_tombstones_user_change_seq ON entry_tombstones(user_id, change_seq);
+
+-- Outcome of each mutation pushed through POST /sync, so retried pushes are not applied twice
+CREATE TABLE sync_mutations (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    mutation_id UUID NOT NULL,
+    entry_id UUID NOT NULL,
+    status VARCHAR(20) NOT NULL, -- applied or conflict
+    reason VARCHAR(50), -- Why a conflicting mutation was not applied
+    version BIGINT, -- Entry version after the mutation, or the current one on conflict
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, mutation_id)
+);
+
+CREATE INDEX idx_sync_mutations_created_at ON sync_mutations(created_at);
@@ -0,0 +1,37 @@
+-- Failed login attempts, counted over a sliding window per account and per client IP
+CREATE TABLE login_failures (
+    id BIGSERIAL PRIMARY KEY,
+    email VARCHAR(255) NOT NULL, -- Lower-cased; need not belong to an account
+    ip VARCHAR(45) NOT NULL,
+    attempted_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_login_failures_email_attempted_at ON login_failures(email, attempted_at);
+CREATE INDEX idx_login_failures_ip_attempted_at ON login_failures(ip, attempted_at);
+
+-- Current lockout per account or IP. lockout_count drives the exponential
+-- backoff and is forgotten once the row is purged.
+CREATE TABLE login_lockouts (
+    scope VARCHAR(10) NOT NULL, -- account or ip
+    key VARCHAR(255) NOT NULL, -- Lower-cased email or IP address
+    lockout_count INTEGER NOT NULL,
+    locked_until TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (scope, key)
+);
+
+CREATE INDEX idx_login_lockouts_locked_until ON login_lockouts(locked_until);
+
+-- Audit trail of every lockout
+CREATE TABLE login_lockout_events (
+    id UUID PRIMARY KEY,
+    scope VARCHAR(10) NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    ip VARCHAR(45) NOT NULL, -- Address of the attempt that triggered the lockout
+    failures BIGINT NOT NULL, -- Failures in the window when the lockout started
+    lockout_count INTEGER NOT NULL,
+    locked_until TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_login_lockout_events_scope_key ON login_lockout_events(scope, key, created_at);
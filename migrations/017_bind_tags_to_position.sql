@@ -0,0 +1,5 @@
+-- Tags are sealed with their position in the associated data, so they cannot
+-- be reordered within an entry. FALSE for rows sealed before, whose tags all
+-- share one associated data until the re-encryption job re-seals them.
+ALTER TABLE journal_entries ADD COLUMN tags_position_bound BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE journal_entry_revisions ADD COLUMN tags_position_bound BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,6 @@
+-- Entries are indexed for search in the background. The version that could
+-- not be decrypted for indexing is recorded, so it is not retried until the
+-- entry changes.
+ALTER TABLE journal_entries ADD COLUMN search_index_failed_version BIGINT;
+
+CREATE INDEX idx_journal_entries_search_pending ON journal_entries(id) WHERE NOT search_indexed;
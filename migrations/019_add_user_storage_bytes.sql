@@ -0,0 +1,14 @@
+-- Running total of the bytes each user stores: title, content and tags of
+-- their entries, trashed ones included, and of every revision. Kept up to
+-- date by each write, so quota checks need not scan the user's entries.
+ALTER TABLE users ADD COLUMN storage_bytes BIGINT NOT NULL DEFAULT 0;
+
+UPDATE users u SET storage_bytes =
+    COALESCE((
+        SELECT SUM(octet_length(title) + octet_length(content) + COALESCE(octet_length(array_to_string(tags, '')), 0))
+        FROM journal_entries WHERE user_id = u.id
+    ), 0)
+  + COALESCE((
+        SELECT SUM(octet_length(title) + octet_length(content) + COALESCE(octet_length(array_to_string(tags, '')), 0))
+        FROM journal_entry_revisions WHERE user_id = u.id
+    ), 0);
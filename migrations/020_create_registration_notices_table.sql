@@ -0,0 +1,8 @@
+-- Emails sent in conceal registration mode about sign-ups that created no
+-- account, counted per address to limit how many one mailbox gets.
+CREATE TABLE registration_notices (
+    email VARCHAR(254) NOT NULL, -- Lower-cased recipient, which need not have an account
+    sent_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_registration_notices_email_sent_at ON registration_notices(email, sent_at);
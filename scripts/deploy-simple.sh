@@ -59,7 +59,7 @@ docker-compose ps
 echo ""
 echo "🔗 Your API is available at:"
 echo "   http://$(hostname -I | awk '{print $1}'):3000"
-echo "   Health check: http://$(hostname -I | awk '{print $1}'):3000/health"
+echo "   Health check: http://$(hostname -I | awk '{print $1}'):3000/health/ready"
 echo ""
 echo "📋 Useful commands:"
 echo "   View logs: docker-compose logs -f api"
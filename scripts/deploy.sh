@@ -66,4 +66,4 @@ kubectl get pods -n kryptic-journal
 echo ""
 echo "🔗 To access your API:"
 echo "   Local: kubectl port-forward svc/kryptic-journal-api-service -n kryptic-journal 3000:80"
-echo "   Then visit: http://localhost:3000/health"
\ No newline at end of file
+echo "   Then visit: http://localhost:3000/health/ready"
\ No newline at end of file
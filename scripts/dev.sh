@@ -37,7 +37,7 @@ echo "✅ Development environment is ready!"
 echo ""
 echo "🔗 Services:"
 echo "   API: http://localhost:3000"
-echo "   Health Check: http://localhost:3000/health"
+echo "   Health Check: http://localhost:3000/health/ready"
 echo "   PostgreSQL: localhost:5432"
 echo ""
 echo "📋 Useful commands:"
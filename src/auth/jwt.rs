@@ -1,6 +1,6 @@
 use axum::{
     extract::{Request, State},
-    http::{header::AUTHORIZATION, StatusCode},
+    http::header::AUTHORIZATION,
     middleware::Next,
     response::Response,
 };
@@ -9,49 +9,63 @@ use serde::{Deserialize, Serialize};
 use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
+use crate::config::AuthConfig;
+use crate::error::AppError;
 use crate::AppState;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Default lifetime of an access token. Clients keep sessions alive with refresh tokens.
+pub const DEFAULT_ACCESS_TOKEN_TTL_MINUTES: i64 = 15;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // User ID
     pub exp: i64,    // Expiration time
     pub iat: i64,    // Issued at
+    pub jti: Uuid,   // Token ID, used for revocation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat_ms: Option<i64>, // Issued at in milliseconds, to order tokens against revocation cut-offs
 }
 
 impl Claims {
-    pub fn new(user_id: Uuid) -> Self {
+    pub fn new(user_id: Uuid, ttl: Duration) -> Self {
         let now = OffsetDateTime::now_utc();
-        let exp = now + Duration::hours(24); // Token expires in 24 hours
+        let exp = now + ttl;
 
         Self {
             sub: user_id.to_string(),
             exp: exp.unix_timestamp(),
             iat: now.unix_timestamp(),
+            jti: Uuid::new_v4(),
+            iat_ms: Some((now.unix_timestamp_nanos() / 1_000_000) as i64),
         }
     }
+
+    /// Issue time in milliseconds. Tokens minted before `iat_ms` existed
+    /// count as issued at the start of their second.
+    pub fn issued_at_ms(&self) -> i64 {
+        self.iat_ms.unwrap_or(self.iat * 1000)
+    }
 }
 
-pub fn create_jwt(user_id: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
-    let claims = Claims::new(user_id);
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    let key = EncodingKey::from_secret(secret.as_ref());
+pub fn create_jwt(config: &AuthConfig, user_id: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = Claims::new(user_id, config.access_token_ttl);
+    let key = EncodingKey::from_secret(config.jwt_secret.as_ref());
     
     encode(&Header::default(), &claims, &key)
 }
 
-pub fn verify_jwt(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    let key = DecodingKey::from_secret(secret.as_ref());
+pub fn verify_jwt(config: &AuthConfig, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let key = DecodingKey::from_secret(config.jwt_secret.as_ref());
     let validation = Validation::default();
     
     decode::<Claims>(token, &key, &validation).map(|data| data.claims)
 }
 
 pub async fn auth_middleware(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
     mut request: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
+) -> Result<Response, AppError> {
     let auth_header = request
         .headers()
         .get(AUTHORIZATION)
@@ -59,21 +73,29 @@ pub async fn auth_middleware(
 
     let auth_header = match auth_header {
         Some(header) => header,
-        None => return Err(StatusCode::UNAUTHORIZED),
+        None => return Err(AppError::Unauthorized),
     };
 
     if !auth_header.starts_with("Bearer ") {
-        return Err(StatusCode::UNAUTHORIZED);
+        return Err(AppError::Unauthorized);
     }
 
     let token = auth_header.trim_start_matches("Bearer ");
     
-    match verify_jwt(token) {
-        Ok(claims) => {
-            // Add user ID to request extensions for use in handlers
-            request.extensions_mut().insert(claims.sub);
-            Ok(next.run(request).await)
-        }
-        Err(_) => Err(StatusCode::UNAUTHORIZED),
+    let claims = verify_jwt(&state.config.auth, token).map_err(|_| AppError::Unauthorized)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| AppError::Unauthorized)?;
+    tracing::Span::current().record("user_id", tracing::field::display(user_id));
+
+    let revoked = state
+        .revocations
+        .is_revoked(user_id, &claims)
+        .await?;
+    if revoked {
+        return Err(AppError::Unauthorized);
     }
+
+    // Add user ID and claims to request extensions for use in handlers
+    request.extensions_mut().insert(claims.sub.clone());
+    request.extensions_mut().insert(claims);
+    Ok(next.run(request).await)
 } 
\ No newline at end of file
@@ -1 +1,7 @@
-pub mod jwt; 
\ No newline at end of file
+pub mod jwt;
+pub mod password;
+pub mod password_reset;
+pub mod registration_notices;
+pub mod refresh;
+pub mod revocation;
+pub mod throttle;
\ No newline at end of file
@@ -0,0 +1,34 @@
+use argon2::password_hash::{rand_core::OsRng, SaltString};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> Result<bool, argon2::password_hash::Error> {
+    let parsed_hash = PasswordHash::new(hash)?;
+    Ok(Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok())
+}
+
+/// Hash of a random password, with the same parameters as real ones.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| {
+        hash_password(&Uuid::new_v4().to_string()).expect("hashing a random password cannot fail")
+    })
+}
+
+/// Compute the hash once at startup, so the first login for an unknown
+/// email is not slower than the rest.
+pub fn init_dummy_hash() {
+    dummy_hash();
+}
+
+/// Spend as long as checking a real password, for logins with an unknown
+/// email, so response times do not reveal which emails have accounts.
+pub fn verify_dummy(password: &str) {
+    let _ = verify_password(password, dummy_hash());
+}
@@ -0,0 +1,143 @@
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use sqlx::{PgConnection, PgPool};
+use std::time::Duration;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::shutdown::Shutdown;
+
+pub const DEFAULT_RESET_TOKEN_TTL_MINUTES: i64 = 60;
+
+const RESET_TOKEN_BYTES: usize = 32;
+
+/// How long used and expired tokens are kept before being purged.
+const RESET_TOKEN_MEMORY: time::Duration = time::Duration::days(1);
+
+/// Most reset tokens, and so emails, issued to one account within
+/// [`RESET_WINDOW`], so the endpoint cannot be used to flood a mailbox.
+const MAX_RESETS_PER_WINDOW: i64 = 3;
+
+const RESET_WINDOW: time::Duration = time::Duration::hours(1);
+
+/// How often old reset tokens are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Reset tokens are stored as SHA-256 digests, like refresh tokens, so a
+/// database leak does not allow taking over accounts.
+fn hash_reset_token(token: &str) -> String {
+    hex::encode(digest(&SHA256, token.as_bytes()))
+}
+
+/// Issue a reset token for the user, expiring any still unused one, so
+/// only the most recent email works. Returns `None` once the account has
+/// had [`MAX_RESETS_PER_WINDOW`] tokens within [`RESET_WINDOW`].
+pub async fn issue_reset_token(
+    db: &PgPool,
+    user_id: Uuid,
+    ttl: time::Duration,
+) -> Result<Option<String>, AppError> {
+    let mut bytes = [0u8; RESET_TOKEN_BYTES];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| AppError::Internal("reset token generation failed".to_string()))?;
+    let token = hex::encode(bytes);
+    let now = OffsetDateTime::now_utc();
+
+    let mut tx = db.begin().await?;
+
+    // Serialise issuing per user, so concurrent requests cannot exceed the limit
+    sqlx::query("SELECT id FROM users WHERE id = $1 FOR UPDATE")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // Superseded tokens are expired rather than deleted, so they still count
+    let recent = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM password_reset_tokens WHERE user_id = $1 AND created_at > $2"
+    )
+    .bind(user_id)
+    .bind(now - RESET_WINDOW)
+    .fetch_one(&mut *tx)
+    .await?;
+    if recent >= MAX_RESETS_PER_WINDOW {
+        return Ok(None);
+    }
+
+    sqlx::query(
+        "UPDATE password_reset_tokens SET expires_at = $1 WHERE user_id = $2 AND used_at IS NULL AND expires_at > $1"
+    )
+    .bind(now)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO password_reset_tokens (id, user_id, token_hash, expires_at, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(hash_reset_token(&token))
+    .bind(now + ttl)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(Some(token))
+}
+
+/// Mark a reset token used and return its user, if it is valid. Runs in
+/// the caller's transaction, so the token stays usable if the reset fails.
+pub async fn consume_reset_token(
+    conn: &mut PgConnection,
+    token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let now = OffsetDateTime::now_utc();
+
+    sqlx::query_scalar::<_, Uuid>(
+        r#"
+        UPDATE password_reset_tokens SET used_at = $1
+        WHERE token_hash = $2 AND used_at IS NULL AND expires_at > $1
+        RETURNING user_id
+        "#
+    )
+    .bind(now)
+    .bind(hash_reset_token(token))
+    .fetch_optional(conn)
+    .await
+}
+
+/// Drop reset tokens a day after they were used or expired.
+pub async fn purge_expired(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let cutoff = OffsetDateTime::now_utc() - RESET_TOKEN_MEMORY;
+
+    let result = sqlx::query(
+        "DELETE FROM password_reset_tokens WHERE expires_at < $1 OR used_at < $1"
+    )
+    .bind(cutoff)
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Periodically purge old reset tokens. Runs until shutdown.
+pub async fn run_purger(db: PgPool, shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.clone().triggered() => return,
+        }
+        match purge_expired(&db).await {
+            Ok(purged) if purged > 0 => tracing::info!(purged, "Purged old password reset tokens"),
+            Ok(_) => {}
+            Err(err) => tracing::warn!(error = %err, "Failed to purge password reset tokens"),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::db::models::RefreshToken;
+
+/// Default lifetime of a single refresh token. Every rotation starts a new window.
+pub const DEFAULT_REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+
+const REFRESH_TOKEN_BYTES: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum RefreshError {
+    #[error("Refresh token is invalid or expired")]
+    Invalid,
+    #[error("Refresh token was already used")]
+    Reused,
+    #[error("Token generation failed")]
+    Generation,
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Refresh tokens are stored as SHA-256 digests so a database leak does not
+/// hand out usable sessions.
+pub fn hash_refresh_token(token: &str) -> String {
+    hex::encode(digest(&SHA256, token.as_bytes()))
+}
+
+fn generate_refresh_token() -> Result<String, RefreshError> {
+    let mut bytes = [0u8; REFRESH_TOKEN_BYTES];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| RefreshError::Generation)?;
+
+    Ok(hex::encode(bytes))
+}
+
+async fn insert_refresh_token(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    family_id: Uuid,
+    ttl: Duration,
+) -> Result<String, RefreshError> {
+    let token = generate_refresh_token()?;
+    let now = OffsetDateTime::now_utc();
+
+    sqlx::query(
+        r#"
+        INSERT INTO refresh_tokens (id, user_id, family_id, token_hash, expires_at, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(family_id)
+    .bind(hash_refresh_token(&token))
+    .bind(now + ttl)
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(token)
+}
+
+/// Issue the first refresh token of a new family, e.g. on login.
+pub async fn issue_refresh_token(
+    db: &PgPool,
+    user_id: Uuid,
+    ttl: Duration,
+) -> Result<String, RefreshError> {
+    let mut conn = db.acquire().await?;
+    insert_refresh_token(&mut conn, user_id, Uuid::new_v4(), ttl).await
+}
+
+/// Exchange a refresh token for a new one in the same family.
+///
+/// Presenting a token that has already been rotated means it was copied
+/// somewhere, so the whole family is revoked and the caller must log in again.
+pub async fn rotate_refresh_token(
+    db: &PgPool,
+    presented: &str,
+    ttl: Duration,
+) -> Result<(Uuid, String), RefreshError> {
+    let mut tx = db.begin().await?;
+
+    let token = sqlx::query_as::<_, RefreshToken>(
+        "SELECT id, user_id, family_id, expires_at, used_at, revoked_at FROM refresh_tokens WHERE token_hash = $1 FOR UPDATE"
+    )
+    .bind(hash_refresh_token(presented))
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(RefreshError::Invalid)?;
+
+    let now = OffsetDateTime::now_utc();
+
+    if token.used_at.is_some() {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = $1 WHERE family_id = $2 AND revoked_at IS NULL"
+        )
+        .bind(now)
+        .bind(token.family_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        tracing::warn!(
+            user_id = %token.user_id,
+            family_id = %token.family_id,
+            "Refresh token reuse detected; revoked token family"
+        );
+        return Err(RefreshError::Reused);
+    }
+
+    if token.revoked_at.is_some() || token.expires_at <= now {
+        return Err(RefreshError::Invalid);
+    }
+
+    sqlx::query("UPDATE refresh_tokens SET used_at = $1 WHERE id = $2")
+        .bind(now)
+        .bind(token.id)
+        .execute(&mut *tx)
+        .await?;
+
+    let new_token = insert_refresh_token(&mut tx, token.user_id, token.family_id, ttl).await?;
+    tx.commit().await?;
+
+    Ok((token.user_id, new_token))
+}
+
+/// Revoke the family of the given refresh token, provided it belongs to the user.
+pub async fn revoke_refresh_family(
+    db: &PgPool,
+    user_id: Uuid,
+    presented: &str,
+) -> Result<(), RefreshError> {
+    sqlx::query(
+        r#"
+        UPDATE refresh_tokens SET revoked_at = $1
+        WHERE revoked_at IS NULL AND user_id = $2
+          AND family_id = (SELECT family_id FROM refresh_tokens WHERE token_hash = $3)
+        "#
+    )
+    .bind(OffsetDateTime::now_utc())
+    .bind(user_id)
+    .bind(hash_refresh_token(presented))
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Revoke every outstanding refresh token of the user.
+pub async fn revoke_all_refresh_tokens<'e>(
+    db: impl PgExecutor<'e>,
+    user_id: Uuid,
+) -> Result<(), RefreshError> {
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL")
+        .bind(OffsetDateTime::now_utc())
+        .bind(user_id)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
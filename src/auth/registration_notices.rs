@@ -0,0 +1,83 @@
+use sqlx::PgPool;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+use crate::shutdown::Shutdown;
+
+/// Most notices about sign-ups that created no account sent to one address
+/// within [`NOTICE_WINDOW`], so `conceal` registration cannot be used to
+/// flood a mailbox. The same limit as for password reset emails.
+const MAX_NOTICES_PER_WINDOW: i64 = 3;
+
+const NOTICE_WINDOW: time::Duration = time::Duration::hours(1);
+
+/// How often notices older than the window are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Notices are counted per lower-cased address, whether or not it belongs
+/// to an account, as a taken username is reported to an unregistered one.
+fn address_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Record a notice to `email`, unless the address has already had
+/// [`MAX_NOTICES_PER_WINDOW`] within [`NOTICE_WINDOW`]. Returns whether the
+/// notice may be sent.
+pub async fn record_notice(db: &PgPool, email: &str) -> Result<bool, sqlx::Error> {
+    let email = address_key(email);
+    let now = OffsetDateTime::now_utc();
+
+    let mut tx = db.begin().await?;
+
+    // Serialise per address, so concurrent sign-ups cannot exceed the limit
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(&email)
+        .execute(&mut *tx)
+        .await?;
+
+    let recent = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM registration_notices WHERE email = $1 AND sent_at > $2"
+    )
+    .bind(&email)
+    .bind(now - NOTICE_WINDOW)
+    .fetch_one(&mut *tx)
+    .await?;
+    if recent >= MAX_NOTICES_PER_WINDOW {
+        return Ok(false);
+    }
+
+    sqlx::query("INSERT INTO registration_notices (email, sent_at) VALUES ($1, $2)")
+        .bind(&email)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Drop notices that no longer count towards the limit.
+pub async fn purge_expired(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM registration_notices WHERE sent_at < $1")
+        .bind(OffsetDateTime::now_utc() - NOTICE_WINDOW)
+        .execute(db)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Periodically purge old registration notices. Runs until shutdown.
+pub async fn run_purger(db: PgPool, shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.clone().triggered() => return,
+        }
+        match purge_expired(&db).await {
+            Ok(purged) if purged > 0 => tracing::info!(purged, "Purged old registration notices"),
+            Ok(_) => {}
+            Err(err) => tracing::warn!(error = %err, "Failed to purge registration notices"),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use sqlx::{FromRow, PgPool};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::auth::jwt::Claims;
+use crate::shutdown::Shutdown;
+
+/// How long a "not revoked" answer from Postgres is trusted before asking again.
+/// This bounds how long a revocation made on another replica can go unnoticed.
+const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// How often expired revocations are purged from Postgres and the cache.
+const PURGE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Most "not revoked" answers kept between purges. Past this, stale
+/// answers are dropped, and if that is not enough the cache starts over.
+const MAX_VERIFIED: usize = 100_000;
+
+#[derive(FromRow)]
+struct RevocationRow {
+    revoked: bool,
+    tokens_valid_after: Option<OffsetDateTime>,
+}
+
+/// A cut-off in milliseconds, rounded up: tokens issued in the same
+/// millisecond as a "log out everywhere" are revoked along with older ones.
+fn cutoff_ms(cutoff: OffsetDateTime) -> i64 {
+    let nanos = cutoff.unix_timestamp_nanos();
+    (nanos / 1_000_000 + i128::from(nanos % 1_000_000 > 0)) as i64
+}
+
+#[derive(Default)]
+struct RevocationCache {
+    /// Revoked token IDs and their expiry, trusted until the token expires.
+    revoked: HashMap<Uuid, i64>,
+    /// Per-user "log out everywhere" cut-offs in unix milliseconds.
+    valid_after: HashMap<Uuid, i64>,
+    /// Token IDs recently confirmed as not revoked.
+    verified: HashMap<Uuid, Instant>,
+}
+
+impl RevocationCache {
+    fn mark_verified(&mut self, jti: Uuid) {
+        if self.verified.len() >= MAX_VERIFIED {
+            self.verified.retain(|_, checked| checked.elapsed() < NEGATIVE_CACHE_TTL);
+            if self.verified.len() >= MAX_VERIFIED {
+                self.verified.clear();
+            }
+        }
+        self.verified.insert(jti, Instant::now());
+    }
+}
+
+/// Postgres-backed list of revoked access tokens with an in-process cache.
+#[derive(Clone)]
+pub struct RevocationStore {
+    db: PgPool,
+    cache: Arc<RwLock<RevocationCache>>,
+}
+
+impl RevocationStore {
+    pub fn new(db: PgPool) -> Self {
+        Self {
+            db,
+            cache: Arc::new(RwLock::new(RevocationCache::default())),
+        }
+    }
+
+    pub async fn is_revoked(&self, user_id: Uuid, claims: &Claims) -> Result<bool, sqlx::Error> {
+        {
+            let cache = self.cache.read().expect("revocation cache poisoned");
+            if cache.revoked.contains_key(&claims.jti) {
+                return Ok(true);
+            }
+            if cache.valid_after.get(&user_id).is_some_and(|cutoff| claims.issued_at_ms() < *cutoff) {
+                return Ok(true);
+            }
+            if cache
+                .verified
+                .get(&claims.jti)
+                .is_some_and(|checked| checked.elapsed() < NEGATIVE_CACHE_TTL)
+            {
+                return Ok(false);
+            }
+        }
+
+        let row = sqlx::query_as::<_, RevocationRow>(
+            r#"
+            SELECT
+                EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $1) AS revoked,
+                (SELECT tokens_valid_after FROM users WHERE id = $2) AS tokens_valid_after
+            "#
+        )
+        .bind(claims.jti)
+        .bind(user_id)
+        .fetch_one(&self.db)
+        .await?;
+
+        let cutoff = row.tokens_valid_after.map(cutoff_ms);
+        let revoked = row.revoked || cutoff.is_some_and(|cutoff| claims.issued_at_ms() < cutoff);
+
+        let mut cache = self.cache.write().expect("revocation cache poisoned");
+        if let Some(cutoff) = cutoff {
+            cache.valid_after.insert(user_id, cutoff);
+        }
+        if row.revoked {
+            cache.revoked.insert(claims.jti, claims.exp);
+        } else if !revoked {
+            cache.mark_verified(claims.jti);
+        }
+
+        Ok(revoked)
+    }
+
+    /// Revoke a single access token until it expires.
+    pub async fn revoke(&self, user_id: Uuid, claims: &Claims) -> Result<(), sqlx::Error> {
+        let expires_at = OffsetDateTime::from_unix_timestamp(claims.exp)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+
+        sqlx::query(
+            "INSERT INTO revoked_tokens (jti, user_id, expires_at, revoked_at) VALUES ($1, $2, $3, $4) ON CONFLICT (jti) DO NOTHING"
+        )
+        .bind(claims.jti)
+        .bind(user_id)
+        .bind(expires_at)
+        .bind(OffsetDateTime::now_utc())
+        .execute(&self.db)
+        .await?;
+
+        let mut cache = self.cache.write().expect("revocation cache poisoned");
+        cache.verified.remove(&claims.jti);
+        cache.revoked.insert(claims.jti, claims.exp);
+
+        Ok(())
+    }
+
+    /// Revoke every access token issued to the user up to now.
+    pub async fn revoke_all(&self, user_id: Uuid) -> Result<(), sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+
+        sqlx::query("UPDATE users SET tokens_valid_after = $1 WHERE id = $2")
+            .bind(now)
+            .bind(user_id)
+            .execute(&self.db)
+            .await?;
+
+        self.cache_revoke_all(user_id, now);
+        Ok(())
+    }
+
+    /// Note a cut-off already written to `users.tokens_valid_after`, e.g. in
+    /// a transaction that also changed the password.
+    pub fn cache_revoke_all(&self, user_id: Uuid, cutoff: OffsetDateTime) {
+        let mut cache = self.cache.write().expect("revocation cache poisoned");
+        cache.valid_after.insert(user_id, cutoff_ms(cutoff));
+    }
+
+    /// Drop revocations for tokens that have expired on their own.
+    pub async fn purge_expired(&self) -> Result<u64, sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+
+        let result = sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < $1")
+            .bind(now)
+            .execute(&self.db)
+            .await?;
+
+        let mut cache = self.cache.write().expect("revocation cache poisoned");
+        cache.revoked.retain(|_, exp| *exp >= now.unix_timestamp());
+        cache.verified.retain(|_, checked| checked.elapsed() < NEGATIVE_CACHE_TTL);
+
+        Ok(result.rows_affected())
+    }
+
+    /// Periodically purge expired revocations. Runs until shutdown.
+    pub async fn run_purger(self, shutdown: Shutdown) {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.clone().triggered() => return,
+            }
+            match self.purge_expired().await {
+                Ok(purged) if purged > 0 => tracing::info!(purged, "Purged expired token revocations"),
+                Ok(_) => {}
+                Err(err) => tracing::warn!(error = %err, "Failed to purge token revocations"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cutoff_rounds_up_to_the_millisecond() {
+        let second = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        assert_eq!(cutoff_ms(second), 1_700_000_000_000);
+        assert_eq!(cutoff_ms(second + time::Duration::microseconds(1)), 1_700_000_000_001);
+        assert_eq!(cutoff_ms(second + time::Duration::milliseconds(250)), 1_700_000_000_250);
+    }
+
+    #[test]
+    fn token_issued_later_in_the_cutoff_second_survives() {
+        let cutoff = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap()
+            + time::Duration::milliseconds(250);
+        let mut claims = Claims::new(Uuid::new_v4(), time::Duration::minutes(15));
+        claims.iat = 1_700_000_000;
+
+        claims.iat_ms = Some(1_700_000_000_100);
+        assert!(claims.issued_at_ms() < cutoff_ms(cutoff));
+
+        claims.iat_ms = Some(1_700_000_000_400);
+        assert!(claims.issued_at_ms() >= cutoff_ms(cutoff));
+
+        // Tokens without `iat_ms` cannot be ordered within their second
+        claims.iat_ms = None;
+        assert!(claims.issued_at_ms() < cutoff_ms(cutoff));
+    }
+}
@@ -0,0 +1,217 @@
+use sqlx::PgPool;
+use std::net::IpAddr;
+use std::time::Duration;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::config::LoginThrottleConfig;
+use crate::shutdown::Shutdown;
+
+pub const DEFAULT_MAX_ACCOUNT_FAILURES: i64 = 5;
+pub const DEFAULT_MAX_IP_FAILURES: i64 = 20;
+pub const DEFAULT_FAILURE_WINDOW_MINUTES: i64 = 15;
+pub const DEFAULT_LOCKOUT_SECS: i64 = 60;
+pub const DEFAULT_MAX_LOCKOUT_SECS: i64 = 60 * 60;
+
+/// How long after a lockout ends its backoff is remembered. A further
+/// lockout within this period lasts twice as long as the previous one.
+const BACKOFF_MEMORY: time::Duration = time::Duration::days(1);
+
+/// How often old failures and lockouts are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(600);
+
+const SCOPE_ACCOUNT: &str = "account";
+const SCOPE_IP: &str = "ip";
+
+/// Failures are tracked per lower-cased email, whether or not it belongs to
+/// an account, so lockouts do not reveal which emails are registered.
+fn account_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// How much longer logins for `email` or from `ip` are locked out, if they are.
+pub async fn lockout_remaining(
+    db: &PgPool,
+    email: &str,
+    ip: IpAddr,
+) -> Result<Option<Duration>, sqlx::Error> {
+    let now = OffsetDateTime::now_utc();
+    let locked_until = sqlx::query_scalar::<_, Option<OffsetDateTime>>(
+        r#"
+        SELECT MAX(locked_until) FROM login_lockouts
+        WHERE ((scope = $1 AND key = $2) OR (scope = $3 AND key = $4)) AND locked_until > $5
+        "#
+    )
+    .bind(SCOPE_ACCOUNT)
+    .bind(account_key(email))
+    .bind(SCOPE_IP)
+    .bind(ip.to_string())
+    .bind(now)
+    .fetch_one(db)
+    .await?;
+
+    Ok(locked_until.map(|until| (until - now).unsigned_abs()))
+}
+
+/// Record a failed login and lock out the account and/or IP once they
+/// reach their failure limit within the window.
+pub async fn record_failure(
+    db: &PgPool,
+    config: &LoginThrottleConfig,
+    email: &str,
+    ip: IpAddr,
+) -> Result<(), sqlx::Error> {
+    let now = OffsetDateTime::now_utc();
+    let email = account_key(email);
+    let ip = ip.to_string();
+
+    sqlx::query("INSERT INTO login_failures (email, ip, attempted_at) VALUES ($1, $2, $3)")
+        .bind(&email)
+        .bind(&ip)
+        .bind(now)
+        .execute(db)
+        .await?;
+
+    let window_start = now - config.failure_window;
+    let (account_failures, ip_failures) = sqlx::query_as::<_, (i64, i64)>(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM login_failures WHERE email = $1 AND attempted_at > $3),
+            (SELECT COUNT(*) FROM login_failures WHERE ip = $2 AND attempted_at > $3)
+        "#
+    )
+    .bind(&email)
+    .bind(&ip)
+    .bind(window_start)
+    .fetch_one(db)
+    .await?;
+
+    if account_failures >= config.max_account_failures {
+        lock(db, config, SCOPE_ACCOUNT, &email, &ip, account_failures, now).await?;
+    }
+    if ip_failures >= config.max_ip_failures {
+        lock(db, config, SCOPE_IP, &ip, &ip, ip_failures, now).await?;
+    }
+    Ok(())
+}
+
+/// Start or extend a lockout, doubling its length each time up to the
+/// configured maximum, and record the event. Does nothing if another
+/// replica has already locked `key`.
+async fn lock(
+    db: &PgPool,
+    config: &LoginThrottleConfig,
+    scope: &str,
+    key: &str,
+    ip: &str,
+    failures: i64,
+    now: OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let lockout = sqlx::query_as::<_, (i32, OffsetDateTime)>(
+        r#"
+        INSERT INTO login_lockouts AS l (scope, key, lockout_count, locked_until, updated_at)
+        VALUES ($1, $2, 1, $3 + make_interval(secs => $4), $3)
+        ON CONFLICT (scope, key) DO UPDATE
+        SET lockout_count = l.lockout_count + 1,
+            locked_until = $3 + make_interval(secs => LEAST($4 * power(2, l.lockout_count), $5)),
+            updated_at = $3
+        WHERE l.locked_until <= $3
+        RETURNING lockout_count, locked_until
+        "#
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(now)
+    .bind(config.lockout.as_seconds_f64())
+    .bind(config.max_lockout.as_seconds_f64())
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((lockout_count, locked_until)) = lockout else {
+        return Ok(());
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO login_lockout_events (id, scope, key, ip, failures, lockout_count, locked_until, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(scope)
+    .bind(key)
+    .bind(ip)
+    .bind(failures)
+    .bind(lockout_count)
+    .bind(locked_until)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    tracing::warn!(
+        scope,
+        key,
+        ip,
+        failures,
+        lockout_count,
+        %locked_until,
+        "Login locked out after repeated failures"
+    );
+    Ok(())
+}
+
+/// Forget the account's failures and backoff after a successful login.
+/// The client IP's record is kept, so one good account does not unlock a
+/// guessing run against others.
+pub async fn record_success(db: &PgPool, email: &str) -> Result<(), sqlx::Error> {
+    let email = account_key(email);
+
+    sqlx::query("DELETE FROM login_failures WHERE email = $1")
+        .bind(&email)
+        .execute(db)
+        .await?;
+    sqlx::query("DELETE FROM login_lockouts WHERE scope = $1 AND key = $2")
+        .bind(SCOPE_ACCOUNT)
+        .bind(&email)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Drop failures that have left the window and lockouts whose backoff has
+/// been forgotten. Lockout events are kept.
+pub async fn purge_expired(db: &PgPool, config: &LoginThrottleConfig) -> Result<u64, sqlx::Error> {
+    let now = OffsetDateTime::now_utc();
+
+    let failures = sqlx::query("DELETE FROM login_failures WHERE attempted_at < $1")
+        .bind(now - config.failure_window)
+        .execute(db)
+        .await?;
+    let lockouts = sqlx::query("DELETE FROM login_lockouts WHERE locked_until < $1")
+        .bind(now - BACKOFF_MEMORY)
+        .execute(db)
+        .await?;
+
+    Ok(failures.rows_affected() + lockouts.rows_affected())
+}
+
+/// Periodically purge old login failures and lockouts. Runs until shutdown.
+pub async fn run_purger(db: PgPool, config: LoginThrottleConfig, shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.clone().triggered() => return,
+        }
+        match purge_expired(&db, &config).await {
+            Ok(purged) if purged > 0 => tracing::info!(purged, "Purged old login failures"),
+            Ok(_) => {}
+            Err(err) => tracing::warn!(error = %err, "Failed to purge login failures"),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::error::AppError;
+use crate::AppState;
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Address of the client that sent the request.
+///
+/// This is the peer address, unless the server sits behind
+/// `TRUSTED_PROXY_HOPS` reverse proxies: then it is the address the
+/// outermost trusted proxy recorded in `X-Forwarded-For`. Entries further
+/// left were written by the client and are ignored.
+pub struct ClientIp(pub IpAddr);
+
+fn forwarded_for(parts: &Parts, hops: usize) -> Option<IpAddr> {
+    let entries: Vec<&str> = parts
+        .headers
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    // With fewer entries than proxies, the leftmost is the best guess
+    let index = entries.len().saturating_sub(hops);
+    entries.get(index)?.parse().ok()
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let hops = state.config.server.trusted_proxy_hops;
+        if hops > 0 {
+            if let Some(ip) = forwarded_for(parts, hops) {
+                return Ok(ClientIp(ip));
+            }
+        }
+
+        parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| ClientIp(addr.ip()))
+            .ok_or_else(|| AppError::Internal("peer address not available".to_string()))
+    }
+}
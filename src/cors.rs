@@ -0,0 +1,111 @@
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+            ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::config::CorsConfig;
+use crate::AppState;
+
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE";
+const ALLOWED_HEADERS: &str = "authorization, content-type, if-match, if-none-match, x-request-id";
+const EXPOSED_HEADERS: &str =
+    "etag, x-request-id, retry-after, ratelimit-limit, ratelimit-remaining, ratelimit-reset, ratelimit-policy";
+
+/// The `Access-Control-Allow-Origin` value for `origin`, if it is allowed.
+fn allowed_origin(config: &CorsConfig, origin: &HeaderValue) -> Option<HeaderValue> {
+    let origin_str = origin.to_str().ok()?;
+    if config.allowed_origins.iter().any(|allowed| allowed == "*") {
+        Some(HeaderValue::from_static("*"))
+    } else if config.allowed_origins.iter().any(|allowed| allowed == origin_str) {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+fn add_cors_headers(headers: &mut HeaderMap, allow_origin: HeaderValue) {
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    headers.append(VARY, HeaderValue::from_static("origin"));
+}
+
+/// Answer CORS preflight requests from allowed origins and tag responses to
+/// them. Requests from other origins, and all requests when no origins are
+/// configured, pass through untouched, so a disallowed preflight gets the
+/// route's usual answer to `OPTIONS`.
+pub async fn cors_middleware(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = &state.config.cors;
+    let allow_origin = match request.headers().get(ORIGIN) {
+        Some(origin) if !config.allowed_origins.is_empty() => allowed_origin(config, origin),
+        _ => None,
+    };
+
+    let Some(allow_origin) = allow_origin else {
+        let mut response = next.run(request).await;
+        if !config.allowed_origins.is_empty() {
+            response.headers_mut().append(VARY, HeaderValue::from_static("origin"));
+        }
+        return response;
+    };
+
+    let is_preflight = request.method() == Method::OPTIONS
+        && request.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    if is_preflight {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        add_cors_headers(headers, allow_origin);
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static(ALLOWED_METHODS));
+        headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static(ALLOWED_HEADERS));
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(config.max_age.as_secs()));
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    add_cors_headers(headers, allow_origin);
+    headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(EXPOSED_HEADERS));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            max_age: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn allows_listed_origins_only() {
+        let config = config(&["https://app.example.com"]);
+        let allowed = HeaderValue::from_static("https://app.example.com");
+
+        assert_eq!(allowed_origin(&config, &allowed), Some(allowed));
+        assert_eq!(allowed_origin(&config, &HeaderValue::from_static("https://evil.example.com")), None);
+    }
+
+    #[test]
+    fn wildcard_allows_any_origin() {
+        let config = config(&["*"]);
+        assert_eq!(
+            allowed_origin(&config, &HeaderValue::from_static("https://any.example.com")),
+            Some(HeaderValue::from_static("*"))
+        );
+    }
+}
@@ -1 +1,6 @@
-pub mod models; 
\ No newline at end of file
+use sqlx::migrate::Migrator;
+
+pub mod models;
+
+/// Migrations embedded in the binary, run at startup.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
@@ -1,9 +1,15 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::types::Json;
 use sqlx::FromRow;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, FromRow, Serialize)]
+// Types holding passwords, tokens or journal text implement `Debug` with
+// `redacted_debug!`, listing only the fields that are safe to log.
+use crate::logging::redacted_debug;
+
+#[derive(Clone, FromRow, Serialize)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
@@ -13,54 +19,344 @@ pub struct User {
     pub updated_at: OffsetDateTime,
 }
 
-#[derive(Debug, Deserialize)]
+redacted_debug!(User { id, username, created_at, updated_at });
+
+#[derive(Deserialize)]
 pub struct CreateUser {
     pub username: String,
     pub email: String,
     pub password: String,
 }
 
-#[derive(Debug, Deserialize)]
+redacted_debug!(CreateUser { username });
+
+#[derive(Deserialize)]
 pub struct LoginUser {
     pub email: String,
     pub password: String,
 }
 
-#[derive(Debug, Clone, FromRow, Serialize)]
+redacted_debug!(LoginUser {});
+
+#[derive(Clone, FromRow, Serialize)]
 pub struct JournalEntry {
     pub id: Uuid,
     pub user_id: Uuid,
     pub title: String,
-    pub content: String, // This will be encrypted
+    pub content: String, // Server-encrypted, or client ciphertext for client-encrypted users
     pub mood_score: Option<i32>, // 1-10 scale
     pub tags: Option<Vec<String>>,
+    pub client_kdf: Option<Json<Value>>, // Only set for client-encrypted users
+    pub metadata_encrypted: bool, // Whether title and tags are ciphertexts
+    pub tags_position_bound: bool, // Whether each tag ciphertext is bound to its position
     pub created_at: OffsetDateTime,
     pub updated_at: OffsetDateTime,
+    pub deleted_at: Option<OffsetDateTime>, // Set while the entry is in the trash
+    pub version: i64, // Bumped on every change
 }
 
-#[derive(Debug, Deserialize)]
+redacted_debug!(JournalEntry {
+    id,
+    user_id,
+    mood_score,
+    metadata_encrypted,
+    created_at,
+    updated_at,
+    deleted_at,
+    version,
+});
+
+/// Server-encrypted users send `content`; client-encrypted users send
+/// `ciphertext` and optionally `kdf` instead, which are stored verbatim.
+#[derive(Deserialize)]
 pub struct CreateJournalEntry {
     pub title: String,
-    pub content: String,
+    pub content: Option<String>,
+    pub ciphertext: Option<String>,
+    pub kdf: Option<Value>,
     pub mood_score: Option<i32>,
     pub tags: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize)]
+redacted_debug!(CreateJournalEntry { mood_score });
+
+#[derive(Deserialize)]
 pub struct UpdateJournalEntry {
     pub title: Option<String>,
     pub content: Option<String>,
+    pub ciphertext: Option<String>,
+    pub kdf: Option<Value>,
     pub mood_score: Option<i32>,
     pub tags: Option<Vec<String>>,
+    pub version: Option<i64>, // Alternative to `If-Match`: the version this update is based on
 }
 
-#[derive(Debug, Serialize)]
+redacted_debug!(UpdateJournalEntry { mood_score, version });
+
+/// The complete editable state of an entry, in the form clients send it.
+/// `PATCH /entries/:id` merges its patch into this document.
+#[derive(Serialize, Deserialize)]
+pub struct EntryDocument {
+    // Left empty when a patch removes it, for validation to reject
+    #[serde(default)]
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ciphertext: Option<String>,
+    #[serde(default)]
+    pub kdf: Option<Value>,
+    #[serde(default)]
+    pub mood_score: Option<i32>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+redacted_debug!(EntryDocument { mood_score });
+
+/// Query parameters for `GET /entries`. Timestamps are RFC 3339.
+#[derive(Debug, Deserialize)]
+pub struct EntryFilter {
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub from: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub to: Option<OffsetDateTime>,
+    pub tag: Option<String>,
+    pub mood_min: Option<i32>,
+    pub mood_max: Option<i32>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub updated_since: Option<OffsetDateTime>,
+}
+
+#[derive(Serialize)]
 pub struct JournalEntryResponse {
     pub id: Uuid,
     pub title: String,
-    pub content: String, // This will be decrypted before sending
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>, // Decrypted before sending, server-encrypted users only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ciphertext: Option<String>, // Returned verbatim, client-encrypted users only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<Value>,
     pub mood_score: Option<i32>,
     pub tags: Option<Vec<String>>,
     pub created_at: OffsetDateTime,
     pub updated_at: OffsetDateTime,
-} 
\ No newline at end of file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<OffsetDateTime>,
+    pub version: i64, // Also sent as the entry's ETag
+}
+
+redacted_debug!(JournalEntryResponse {
+    id,
+    mood_score,
+    created_at,
+    updated_at,
+    deleted_at,
+    version,
+}); 
+
+#[derive(Debug, Serialize)]
+pub struct JournalEntryPage {
+    pub entries: Vec<JournalEntryResponse>,
+    pub next_cursor: Option<String>, // Pass back as `cursor` to fetch the next page
+}
+
+/// An earlier version of an entry. `entry.updated_at` is when it was written.
+#[derive(Debug, Clone, FromRow)]
+pub struct JournalEntryRevision {
+    pub revision: i32,
+    #[sqlx(flatten)]
+    pub entry: JournalEntry,
+    pub replaced_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct RevisionSummary {
+    pub revision: i32,
+    pub updated_at: OffsetDateTime,
+    pub replaced_at: OffsetDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JournalEntryRevisionResponse {
+    pub revision: i32,
+    pub replaced_at: OffsetDateTime,
+    pub entry: JournalEntryResponse,
+}
+
+/// Query parameters for `GET /entries/search`.
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+redacted_debug!(SearchQuery { limit });
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    pub entry: JournalEntryResponse,
+    pub score: f64,
+    pub snippet: String, // Content around the first match
+}
+
+redacted_debug!(SearchHit { entry, score });
+
+#[derive(Debug, Serialize)]
+pub struct SearchResults {
+    pub results: Vec<SearchHit>,
+}
+
+/// Query parameters for `GET /sync`.
+#[derive(Debug, Deserialize)]
+pub struct SyncQuery {
+    pub since: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// An entry with the sequence number of its latest change.
+#[derive(Debug, Clone, FromRow)]
+pub struct SyncedEntry {
+    pub change_seq: i64,
+    #[sqlx(flatten)]
+    pub entry: JournalEntry,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncChange {
+    pub entry_id: Uuid,
+    pub seq: i64,
+    pub deleted: bool, // In the trash or deleted for good
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<JournalEntryResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncFeed {
+    pub changes: Vec<SyncChange>,
+    pub next_token: String, // Pass back as `since` to continue from here
+    pub has_more: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncPush {
+    pub mutations: Vec<SyncMutation>,
+}
+
+/// A change made offline. `mutation_id` is chosen by the client and makes
+/// retrying the push safe.
+#[derive(Debug, Deserialize)]
+pub struct SyncMutation {
+    pub mutation_id: Uuid,
+    pub entry_id: Uuid,
+    #[serde(flatten)]
+    pub operation: SyncOperation,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum SyncOperation {
+    Create { entry: CreateJournalEntry },
+    Update { entry: UpdateJournalEntry }, // `entry.version` is the version the edit was based on
+    Delete { version: Option<i64> },
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct SyncMutationResult {
+    pub mutation_id: Uuid,
+    pub entry_id: Uuid,
+    pub status: String, // applied or conflict
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>, // New version, or the server's current one on conflict
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncPushResponse {
+    pub results: Vec<SyncMutationResult>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct RefreshToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub family_id: Uuid,
+    pub expires_at: OffsetDateTime,
+    pub used_at: Option<OffsetDateTime>,
+    pub revoked_at: Option<OffsetDateTime>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+redacted_debug!(RefreshTokenRequest {});
+
+#[derive(Deserialize)]
+pub struct LogoutRequest {
+    pub refresh_token: Option<String>,
+}
+
+redacted_debug!(LogoutRequest {});
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+redacted_debug!(ForgotPasswordRequest {});
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+    /// For client-encrypted accounts: the data key re-wrapped by the client
+    /// under its new password, replacing the stored key material.
+    pub key_material: Option<UpdateClientKeyMaterial>,
+}
+
+redacted_debug!(ResetPasswordRequest {});
+
+#[derive(Debug, Serialize)]
+pub struct ResetPasswordResponse {
+    pub message: &'static str,
+    /// What protects the user's entries now: `server` (a server-held key,
+    /// unaffected by the reset), `replaced` (the key material sent along) or
+    /// `unchanged` (client key material still wrapped under the old secret).
+    pub key_material: &'static str,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct ReencryptionJob {
+    pub id: Uuid,
+    pub phase: String,
+    pub last_id: Option<Uuid>,
+    pub total: i64,
+    pub processed: i64,
+    pub failed: i64,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct ClientKeyMaterial {
+    pub wrapped_key: String,
+    pub kdf: Json<Value>,
+    pub updated_at: OffsetDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateClientKeyMaterial {
+    pub wrapped_key: String,
+    pub kdf: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientKeyMaterialResponse {
+    pub wrapped_key: String,
+    pub kdf: Value,
+    pub updated_at: OffsetDateTime,
+}
\ No newline at end of file
@@ -0,0 +1,190 @@
+use axum::{
+    extract::rejection::JsonRejection,
+    http::{header::{CONTENT_TYPE, RETRY_AFTER}, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::auth::refresh::RefreshError;
+use crate::request_id;
+use crate::utils::encryption::EncryptionError;
+use crate::validation::ValidationErrors;
+
+/// Error returned by request handlers.
+///
+/// Client errors carry a message that is safe to show; server errors keep
+/// their cause for the log and answer with a generic message.
+#[derive(Error, Debug)]
+pub enum AppError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{}", .0.body_text())]
+    InvalidBody(#[from] JsonRejection),
+    #[error("Request failed validation")]
+    Validation(ValidationErrors),
+    #[error("Authentication required")]
+    Unauthorized,
+    #[error("Invalid email or password")]
+    InvalidCredentials,
+    #[error("Refresh token is invalid or expired")]
+    InvalidRefreshToken,
+    #[error("Password reset token is invalid, expired or already used")]
+    InvalidResetToken,
+    #[error("{0} not found")]
+    NotFound(&'static str),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("Entry was changed since the given version")]
+    PreconditionFailed,
+    #[error("{0}")]
+    QuotaExceeded(String),
+    #[error("{message}")]
+    TooManyRequests {
+        message: &'static str,
+        retry_after: std::time::Duration,
+    },
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Encryption error: {0}")]
+    Encryption(#[from] EncryptionError),
+    #[error("Password hashing error: {0}")]
+    PasswordHash(argon2::password_hash::Error),
+    #[error("Token error: {0}")]
+    Token(#[from] jsonwebtoken::errors::Error),
+    #[error("{0}")]
+    Internal(String),
+}
+
+// password_hash errors do not implement std::error::Error, so no #[from]
+impl From<argon2::password_hash::Error> for AppError {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        AppError::PasswordHash(err)
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        AppError::Validation(errors)
+    }
+}
+
+impl From<RefreshError> for AppError {
+    fn from(err: RefreshError) -> Self {
+        match err {
+            RefreshError::Invalid | RefreshError::Reused => AppError::InvalidRefreshToken,
+            RefreshError::Generation => AppError::Internal(err.to_string()),
+            RefreshError::Database(err) => AppError::Database(err),
+        }
+    }
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) | AppError::InvalidResetToken => StatusCode::BAD_REQUEST,
+            AppError::InvalidBody(rejection) => rejection.status(),
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Unauthorized
+            | AppError::InvalidCredentials
+            | AppError::InvalidRefreshToken => StatusCode::UNAUTHORIZED,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            AppError::QuotaExceeded(_) => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Database(_)
+            | AppError::Encryption(_)
+            | AppError::PasswordHash(_)
+            | AppError::Token(_)
+            | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable, machine-readable error code. Clients should match on this
+    /// rather than on `title` or `detail`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::InvalidBody(_) => "invalid_body",
+            AppError::Validation(_) => "validation_failed",
+            AppError::Unauthorized => "unauthorized",
+            AppError::InvalidCredentials => "invalid_credentials",
+            AppError::InvalidRefreshToken => "invalid_refresh_token",
+            AppError::InvalidResetToken => "invalid_reset_token",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::PreconditionFailed => "precondition_failed",
+            AppError::QuotaExceeded(_) => "quota_exceeded",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+            AppError::Database(_)
+            | AppError::Encryption(_)
+            | AppError::PasswordHash(_)
+            | AppError::Token(_)
+            | AppError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+/// RFC 7807 problem details, extended with `code`, `request_id` and, for
+/// validation failures, the messages for each invalid field.
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<ValidationErrors>,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let request_id = request_id::current();
+
+        // The request span already carries the request ID
+        let detail = if status.is_server_error() {
+            tracing::error!(error = %self, "Request failed");
+            "An internal error occurred".to_string()
+        } else {
+            // Serde's message can quote the rejected body, which may hold a password
+            match &self {
+                AppError::InvalidBody(_) => tracing::debug!(code = self.code(), "Request rejected"),
+                _ => tracing::debug!(code = self.code(), error = %self, "Request rejected"),
+            }
+            self.to_string()
+        };
+
+        let errors = match &self {
+            AppError::Validation(errors) => Some(errors.clone()),
+            _ => None,
+        };
+
+        let problem = Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail,
+            code: self.code(),
+            request_id,
+            errors,
+        };
+
+        let mut response = (status, Json(problem)).into_response();
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        if let AppError::TooManyRequests { retry_after, .. } = &self {
+            // Round up, so clients that wait exactly this long are let through
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
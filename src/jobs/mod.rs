@@ -0,0 +1,4 @@
+pub mod reencrypt;
+pub mod search_index;
+pub mod sync;
+pub mod trash;
@@ -0,0 +1,467 @@
+use sqlx::{FromRow, PgPool};
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::db::models::ReencryptionJob;
+use crate::quota::stored_bytes;
+use crate::shutdown::Shutdown;
+use crate::utils::encryption::{
+    get_encryption_service, DataKey, EncryptionError, BOUND_ENVELOPE_PREFIX, ENVELOPE_PREFIX,
+};
+use crate::utils::keys::user_data_key;
+use crate::utils::sealing::{
+    open_content, open_tags, open_title, seal_content, seal_metadata, SealedMetadata,
+};
+
+/// Rows handled per batch; each batch is committed and checkpointed separately.
+const BATCH_SIZE: i64 = 100;
+
+/// Advisory lock key so only one replica runs the job at a time.
+const JOB_LOCK_KEY: i64 = 0x6b6a_7265;
+
+const PHASE_USER_KEYS: &str = "user_keys";
+const PHASE_JOURNAL_ENTRIES: &str = "journal_entries";
+const PHASE_REVISIONS: &str = "journal_entry_revisions";
+
+/// Rows of `journal_entries` or `journal_entry_revisions`, aliased `t`, that
+/// are not sealed the current way: unbound content, plaintext title and
+/// tags, or tags not bound to their position. `$1` is the bound envelope
+/// prefix pattern.
+const NEEDS_RESEAL: &str =
+    "(t.content NOT LIKE $1 OR NOT t.metadata_encrypted OR (t.tags IS NOT NULL AND NOT t.tags_position_bound))";
+
+/// An entry, or one of its revisions, to re-seal.
+#[derive(FromRow)]
+struct PendingEntry {
+    id: Uuid,
+    /// Set for rows of `journal_entry_revisions`.
+    revision: Option<i32>,
+    user_id: Uuid,
+    title: String,
+    content: String,
+    tags: Option<Vec<String>>,
+    metadata_encrypted: bool,
+    tags_position_bound: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum ReencryptError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Move all encrypted data to the active master key and current envelope format.
+///
+/// Wrapped user keys not sealed with the active master key are re-wrapped,
+/// then `journal_entries` and `journal_entry_revisions` are walked in batches
+/// re-sealing legacy ciphertexts bound to their entry and owner, and
+/// encrypting plaintext titles and tags. Content still under the master key
+/// from before the owner had a data key is re-sealed with that key too.
+/// Rows that fail to open are logged, counted as failed and left untouched.
+/// Progress is checkpointed in `reencryption_jobs`, so an interrupted run
+/// picks up where it left off on the next start. On shutdown the job stops
+/// after the batch in progress.
+pub async fn run(db: PgPool, shutdown: Shutdown) {
+    if let Err(err) = run_exclusive(&db, &shutdown).await {
+        tracing::error!(error = %err, "Re-encryption job failed");
+    }
+}
+
+async fn run_exclusive(db: &PgPool, shutdown: &Shutdown) -> Result<(), ReencryptError> {
+    let mut lock_conn = db.acquire().await?;
+
+    let locked = sqlx::query_scalar::<_, bool>("SELECT pg_try_advisory_lock($1)")
+        .bind(JOB_LOCK_KEY)
+        .fetch_one(&mut *lock_conn)
+        .await?;
+
+    if !locked {
+        tracing::info!("Re-encryption job already running on another instance");
+        return Ok(());
+    }
+
+    let result = run_locked(db, shutdown).await;
+
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(JOB_LOCK_KEY)
+        .execute(&mut *lock_conn)
+        .await?;
+
+    result
+}
+
+async fn run_locked(db: &PgPool, shutdown: &Shutdown) -> Result<(), ReencryptError> {
+    let active_key_id = get_encryption_service().active_key_id().to_string();
+    let current_wrap = format!("{}{}:%", ENVELOPE_PREFIX, active_key_id);
+
+    let job = sqlx::query_as::<_, ReencryptionJob>(
+        "SELECT id, phase, last_id, total, processed, failed FROM reencryption_jobs WHERE target_key_id = $1 AND finished_at IS NULL ORDER BY started_at DESC LIMIT 1"
+    )
+    .bind(&active_key_id)
+    .fetch_optional(db)
+    .await?;
+
+    let mut job = match job {
+        Some(job) => {
+            tracing::info!(job_id = %job.id, phase = %job.phase, processed = job.processed, total = job.total, "Resuming re-encryption job");
+            job
+        }
+        None => {
+            let total = sqlx::query_scalar::<_, i64>(&format!(
+                r#"
+                SELECT (SELECT COUNT(*) FROM user_keys WHERE wrapped_key NOT LIKE $2)
+                     + (SELECT COUNT(*) FROM journal_entries t JOIN users u ON u.id = t.user_id
+                        WHERE {NEEDS_RESEAL} AND NOT u.client_encrypted)
+                     + (SELECT COUNT(*) FROM journal_entry_revisions t JOIN users u ON u.id = t.user_id
+                        WHERE {NEEDS_RESEAL} AND NOT u.client_encrypted)
+                "#
+            ))
+            .bind(format!("{}%", BOUND_ENVELOPE_PREFIX))
+            .bind(&current_wrap)
+            .fetch_one(db)
+            .await?;
+
+            if total == 0 {
+                return Ok(());
+            }
+
+            let job = sqlx::query_as::<_, ReencryptionJob>(
+                r#"
+                INSERT INTO reencryption_jobs (id, target_key_id, phase, total)
+                VALUES ($1, $2, $3, $4)
+                RETURNING id, phase, last_id, total, processed, failed
+                "#
+            )
+            .bind(Uuid::new_v4())
+            .bind(&active_key_id)
+            .bind(PHASE_USER_KEYS)
+            .bind(total)
+            .fetch_one(db)
+            .await?;
+
+            tracing::info!(job_id = %job.id, total, target_key_id = %active_key_id, "Started re-encryption job");
+            job
+        }
+    };
+
+    if job.phase == PHASE_USER_KEYS {
+        while rewrap_user_keys_batch(db, &mut job, &current_wrap).await? {
+            checkpoint(db, &job).await?;
+            if shutdown.is_triggered() {
+                paused(&job);
+                return Ok(());
+            }
+        }
+        job.phase = PHASE_JOURNAL_ENTRIES.to_string();
+        job.last_id = None;
+        checkpoint(db, &job).await?;
+    }
+
+    if job.phase == PHASE_JOURNAL_ENTRIES {
+        while reseal_entries_batch(db, &mut job).await? {
+            checkpoint(db, &job).await?;
+            if shutdown.is_triggered() {
+                paused(&job);
+                return Ok(());
+            }
+        }
+        job.phase = PHASE_REVISIONS.to_string();
+        job.last_id = None;
+        checkpoint(db, &job).await?;
+    }
+
+    while reseal_revisions_batch(db, &mut job).await? {
+        checkpoint(db, &job).await?;
+        if shutdown.is_triggered() {
+            paused(&job);
+            return Ok(());
+        }
+    }
+
+    sqlx::query("UPDATE reencryption_jobs SET finished_at = $1, updated_at = $1 WHERE id = $2")
+        .bind(OffsetDateTime::now_utc())
+        .bind(job.id)
+        .execute(db)
+        .await?;
+
+    tracing::info!(job_id = %job.id, processed = job.processed, failed = job.failed, "Re-encryption job finished");
+    Ok(())
+}
+
+fn paused(job: &ReencryptionJob) {
+    tracing::info!(job_id = %job.id, phase = %job.phase, processed = job.processed, "Re-encryption job paused for shutdown");
+}
+
+async fn checkpoint(db: &PgPool, job: &ReencryptionJob) -> Result<(), ReencryptError> {
+    sqlx::query(
+        "UPDATE reencryption_jobs SET phase = $1, last_id = $2, processed = $3, failed = $4, updated_at = $5 WHERE id = $6"
+    )
+    .bind(&job.phase)
+    .bind(job.last_id)
+    .bind(job.processed)
+    .bind(job.failed)
+    .bind(OffsetDateTime::now_utc())
+    .bind(job.id)
+    .execute(db)
+    .await?;
+
+    let percent = if job.total > 0 {
+        (job.processed + job.failed) * 100 / job.total
+    } else {
+        100
+    };
+    tracing::info!(
+        job_id = %job.id,
+        phase = %job.phase,
+        processed = job.processed,
+        failed = job.failed,
+        total = job.total,
+        "Re-encryption progress: {}%",
+        percent.min(100)
+    );
+
+    Ok(())
+}
+
+/// Re-wrap one batch of user keys under the active master key.
+/// Returns `false` once there is nothing left to do.
+async fn rewrap_user_keys_batch(
+    db: &PgPool,
+    job: &mut ReencryptionJob,
+    current_wrap: &str,
+) -> Result<bool, ReencryptError> {
+    let service = get_encryption_service();
+
+    let rows = sqlx::query_as::<_, (Uuid, String)>(
+        r#"
+        SELECT user_id, wrapped_key FROM user_keys
+        WHERE wrapped_key NOT LIKE $1 AND ($2::uuid IS NULL OR user_id > $2)
+        ORDER BY user_id
+        LIMIT $3
+        "#
+    )
+    .bind(current_wrap)
+    .bind(job.last_id)
+    .bind(BATCH_SIZE)
+    .fetch_all(db)
+    .await?;
+
+    let Some((last_id, _)) = rows.last() else {
+        return Ok(false);
+    };
+    job.last_id = Some(*last_id);
+
+    for (user_id, wrapped_key) in rows {
+        let rewrapped = service
+            .unwrap_key(&wrapped_key)
+            .and_then(|data_key| service.wrap_key(&data_key));
+
+        match rewrapped {
+            Ok(rewrapped) => {
+                sqlx::query("UPDATE user_keys SET wrapped_key = $1 WHERE user_id = $2 AND wrapped_key = $3")
+                    .bind(rewrapped)
+                    .bind(user_id)
+                    .bind(&wrapped_key)
+                    .execute(db)
+                    .await?;
+                job.processed += 1;
+            }
+            Err(err) => {
+                tracing::warn!(%user_id, error = %err, "Failed to re-wrap user key");
+                job.failed += 1;
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// Re-seal one batch of journal entries that are not sealed the current
+/// way, using their owner's data key and the entry's associated data.
+/// Client-encrypted entries are opaque to the server and skipped. Returns
+/// `false` once there is nothing left to do.
+async fn reseal_entries_batch(db: &PgPool, job: &mut ReencryptionJob) -> Result<bool, ReencryptError> {
+    let rows = sqlx::query_as::<_, PendingEntry>(&format!(
+        r#"
+        SELECT t.id, NULL::int AS revision, t.user_id, t.title, t.content, t.tags,
+               t.metadata_encrypted, t.tags_position_bound
+        FROM journal_entries t
+        JOIN users u ON u.id = t.user_id
+        WHERE {NEEDS_RESEAL} AND NOT u.client_encrypted AND ($2::uuid IS NULL OR t.id > $2)
+        ORDER BY t.id
+        LIMIT $3
+        "#
+    ))
+    .bind(format!("{}%", BOUND_ENVELOPE_PREFIX))
+    .bind(job.last_id)
+    .bind(BATCH_SIZE)
+    .fetch_all(db)
+    .await?;
+
+    let Some(last) = rows.last() else {
+        return Ok(false);
+    };
+    job.last_id = Some(last.id);
+
+    reseal_rows(db, job, rows).await?;
+    Ok(true)
+}
+
+/// Re-seal the revisions of one batch of entries, like
+/// [`reseal_entries_batch`]. Revisions are walked by entry, so all of an
+/// entry's revisions are handled in the same batch.
+async fn reseal_revisions_batch(db: &PgPool, job: &mut ReencryptionJob) -> Result<bool, ReencryptError> {
+    let rows = sqlx::query_as::<_, PendingEntry>(&format!(
+        r#"
+        SELECT t.entry_id AS id, t.revision, t.user_id, t.title, t.content, t.tags,
+               t.metadata_encrypted, t.tags_position_bound
+        FROM journal_entry_revisions t
+        JOIN users u ON u.id = t.user_id
+        WHERE {NEEDS_RESEAL} AND NOT u.client_encrypted
+          AND t.entry_id IN (
+              SELECT DISTINCT t.entry_id FROM journal_entry_revisions t
+              JOIN users u ON u.id = t.user_id
+              WHERE {NEEDS_RESEAL} AND NOT u.client_encrypted AND ($2::uuid IS NULL OR t.entry_id > $2)
+              ORDER BY t.entry_id
+              LIMIT $3
+          )
+        ORDER BY t.entry_id, t.revision
+        "#
+    ))
+    .bind(format!("{}%", BOUND_ENVELOPE_PREFIX))
+    .bind(job.last_id)
+    .bind(BATCH_SIZE)
+    .fetch_all(db)
+    .await?;
+
+    let Some(last) = rows.last() else {
+        return Ok(false);
+    };
+    job.last_id = Some(last.id);
+
+    reseal_rows(db, job, rows).await?;
+    Ok(true)
+}
+
+async fn reseal_rows(
+    db: &PgPool,
+    job: &mut ReencryptionJob,
+    rows: Vec<PendingEntry>,
+) -> Result<(), ReencryptError> {
+    let user_ids: Vec<Uuid> = rows
+        .iter()
+        .map(|row| row.user_id)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    let mut keys: HashMap<Uuid, DataKey> = HashMap::new();
+    for user_id in user_ids {
+        match user_data_key(db, user_id).await {
+            Ok(data_key) => {
+                keys.insert(user_id, data_key);
+            }
+            Err(err) => tracing::warn!(%user_id, error = %err, "Failed to load user key"),
+        }
+    }
+
+    for row in rows {
+        let Some(data_key) = keys.get(&row.user_id) else {
+            job.failed += 1;
+            continue;
+        };
+
+        let (content, metadata) = match reseal_entry(data_key, &row) {
+            Ok(resealed) => resealed,
+            Err(err) => {
+                tracing::warn!(entry_id = %row.id, revision = row.revision, error = %err, "Failed to re-seal journal entry");
+                job.failed += 1;
+                continue;
+            }
+        };
+
+        // Sealing changes sizes; the owner's storage total moves in the same statement
+        let growth = stored_bytes(&metadata.title, &content, metadata.tags.as_deref())
+            - stored_bytes(&row.title, &row.content, row.tags.as_deref());
+
+        match row.revision {
+            None => {
+                sqlx::query(
+                    r#"
+                    WITH resealed AS (
+                        UPDATE journal_entries
+                        SET content = $1, title = $2, tags = $3, tag_index = $4, metadata_encrypted = TRUE,
+                            tags_position_bound = TRUE
+                        WHERE id = $5 AND content = $6 AND title = $7
+                        RETURNING user_id
+                    )
+                    UPDATE users SET storage_bytes = storage_bytes + $8
+                    FROM resealed WHERE users.id = resealed.user_id
+                    "#
+                )
+                .bind(content)
+                .bind(&metadata.title)
+                .bind(&metadata.tags)
+                .bind(&metadata.tag_index)
+                .bind(row.id)
+                .bind(&row.content)
+                .bind(&row.title)
+                .bind(growth)
+                .execute(db)
+                .await?;
+            }
+            // Revisions never change, only get dropped
+            Some(revision) => {
+                sqlx::query(
+                    r#"
+                    WITH resealed AS (
+                        UPDATE journal_entry_revisions
+                        SET content = $1, title = $2, tags = $3, metadata_encrypted = TRUE, tags_position_bound = TRUE
+                        WHERE entry_id = $4 AND revision = $5
+                        RETURNING user_id
+                    )
+                    UPDATE users SET storage_bytes = storage_bytes + $6
+                    FROM resealed WHERE users.id = resealed.user_id
+                    "#
+                )
+                .bind(content)
+                .bind(&metadata.title)
+                .bind(&metadata.tags)
+                .bind(row.id)
+                .bind(revision)
+                .bind(growth)
+                .execute(db)
+                .await?;
+            }
+        }
+        job.processed += 1;
+    }
+
+    Ok(())
+}
+
+fn reseal_entry(
+    data_key: &DataKey,
+    row: &PendingEntry,
+) -> Result<(String, SealedMetadata), EncryptionError> {
+    let content = open_content(data_key, row.user_id, row.id, &row.content)?;
+
+    let (title, tags) = if row.metadata_encrypted {
+        let title = open_title(data_key, row.user_id, row.id, &row.title)?;
+        let tags = row
+            .tags
+            .as_deref()
+            .map(|tags| open_tags(data_key, row.user_id, row.id, tags, row.tags_position_bound))
+            .transpose()?;
+        (title, tags)
+    } else {
+        (row.title.clone(), row.tags.clone())
+    };
+
+    Ok((
+        seal_content(data_key, row.user_id, row.id, &content)?,
+        seal_metadata(data_key, row.user_id, row.id, &title, tags.as_deref())?,
+    ))
+}
@@ -0,0 +1,134 @@
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::db::models::JournalEntry;
+use crate::routes::journal::{entry_response, ENTRY_COLUMNS};
+use crate::shutdown::Shutdown;
+use crate::utils::encryption::EncryptionError;
+use crate::utils::keys::{user_data_key, EntryCipher};
+use crate::utils::search::index_entry;
+
+/// Entries indexed per transaction.
+const BATCH_SIZE: i64 = 100;
+
+/// How often unindexed entries are looked for.
+const INDEX_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum IndexError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Encryption error: {0}")]
+    Encryption(#[from] EncryptionError),
+}
+
+/// Index one batch of server-encrypted entries that are not in the search
+/// index yet, such as those written before search existed. Returns how many
+/// were indexed and how many failed, or `None` once there is nothing left.
+///
+/// Entries are locked with `SKIP LOCKED`, so a concurrent update either
+/// waits for the batch or is skipped until the next one. An entry that does
+/// not decrypt has its version recorded as failed and is left alone until
+/// it changes.
+pub async fn index_batch(db: &PgPool) -> Result<Option<(u64, u64)>, IndexError> {
+    let mut tx = db.begin().await?;
+
+    let pending = sqlx::query_as::<_, JournalEntry>(&format!(
+        r#"
+        SELECT {ENTRY_COLUMNS} FROM journal_entries
+        WHERE NOT search_indexed
+          AND search_index_failed_version IS DISTINCT FROM version
+          AND user_id IN (SELECT id FROM users WHERE NOT client_encrypted)
+          AND user_id IN (SELECT user_id FROM user_keys)
+        ORDER BY id
+        LIMIT $1
+        FOR UPDATE SKIP LOCKED
+        "#
+    ))
+    .bind(BATCH_SIZE)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if pending.is_empty() {
+        return Ok(None);
+    }
+
+    let user_ids: HashSet<Uuid> = pending.iter().map(|entry| entry.user_id).collect();
+    let mut ciphers: HashMap<Uuid, EntryCipher> = HashMap::new();
+    for user_id in user_ids {
+        match user_data_key(db, user_id).await {
+            Ok(data_key) => {
+                ciphers.insert(user_id, EntryCipher::Server(data_key));
+            }
+            Err(err) => tracing::warn!(%user_id, error = %err, "Failed to load user key for search indexing"),
+        }
+    }
+
+    let (mut indexed, mut failed) = (0, 0);
+    for entry in pending {
+        let (entry_id, user_id, version) = (entry.id, entry.user_id, entry.version);
+
+        let opened = match ciphers.get(&user_id) {
+            Some(cipher @ EntryCipher::Server(data_key)) => {
+                entry_response(cipher, entry).ok().map(|entry| (data_key, entry))
+            }
+            _ => None,
+        };
+
+        let Some((data_key, entry)) = opened else {
+            tracing::warn!(%entry_id, version, "Failed to decrypt entry for search indexing");
+            sqlx::query("UPDATE journal_entries SET search_index_failed_version = $1 WHERE id = $2")
+                .bind(version)
+                .bind(entry_id)
+                .execute(&mut *tx)
+                .await?;
+            failed += 1;
+            continue;
+        };
+
+        index_entry(
+            &mut tx,
+            data_key,
+            user_id,
+            entry_id,
+            &entry.title,
+            entry.content.as_deref().unwrap_or_default(),
+        )
+        .await?;
+        indexed += 1;
+    }
+
+    tx.commit().await?;
+    Ok(Some((indexed, failed)))
+}
+
+/// Periodically index entries missing from the search index, in batches
+/// until none are left. Runs until shutdown, stopping after the batch in
+/// progress.
+pub async fn run(db: PgPool, shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(INDEX_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.clone().triggered() => return,
+        }
+        loop {
+            match index_batch(&db).await {
+                Ok(Some((indexed, failed))) => {
+                    tracing::info!(indexed, failed, "Indexed entries for search");
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::warn!(error = %err, "Failed to index entries for search");
+                    break;
+                }
+            }
+            if shutdown.is_triggered() {
+                return;
+            }
+        }
+    }
+}
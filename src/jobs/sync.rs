@@ -0,0 +1,37 @@
+use sqlx::PgPool;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+use crate::shutdown::Shutdown;
+
+/// How often old sync mutation records are looked for.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long a pushed mutation is remembered. Retrying a push within this
+/// window never applies a mutation twice.
+const MUTATION_RETENTION: time::Duration = time::Duration::days(30);
+
+pub async fn prune_mutations(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM sync_mutations WHERE created_at < $1")
+        .bind(OffsetDateTime::now_utc() - MUTATION_RETENTION)
+        .execute(db)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Periodically forget old sync mutations. Runs until shutdown.
+pub async fn run(db: PgPool, shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.clone().triggered() => return,
+        }
+        match prune_mutations(&db).await {
+            Ok(pruned) if pruned > 0 => tracing::info!(pruned, "Pruned old sync mutations"),
+            Ok(_) => {}
+            Err(err) => tracing::warn!(error = %err, "Failed to prune sync mutations"),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use sqlx::PgPool;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+use crate::quota::{release_purged, STORED_BYTES};
+use crate::shutdown::Shutdown;
+
+/// How often trashed entries past their retention window are looked for.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Trashed entries are kept this many days unless `TRASH_RETENTION_DAYS` says otherwise.
+pub const DEFAULT_TRASH_RETENTION_DAYS: i64 = 30;
+
+/// Permanently delete entries that have been in the trash longer than
+/// `retention`. Their revisions and search index rows go with them, and a
+/// tombstone keeps the deletion in the sync feed under the sequence number
+/// it was trashed with.
+pub async fn purge_expired(db: &PgPool, retention: time::Duration) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(&format!(
+        r#"
+        WITH purged AS (
+            DELETE FROM journal_entries WHERE deleted_at < $1
+            RETURNING id, user_id, change_seq, deleted_at, {STORED_BYTES} AS bytes
+        ),
+        {release}
+        INSERT INTO entry_tombstones (entry_id, user_id, change_seq, deleted_at)
+        SELECT id, user_id, change_seq, deleted_at FROM purged
+        ON CONFLICT (entry_id) DO UPDATE
+        SET user_id = EXCLUDED.user_id, change_seq = EXCLUDED.change_seq, deleted_at = EXCLUDED.deleted_at
+        "#,
+        release = release_purged(),
+    ))
+    .bind(OffsetDateTime::now_utc() - retention)
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Periodically purge expired trash. Runs until shutdown.
+pub async fn run(db: PgPool, retention: time::Duration, shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.clone().triggered() => return,
+        }
+        match purge_expired(&db, retention).await {
+            Ok(purged) if purged > 0 => tracing::info!(purged, "Purged expired trash"),
+            Ok(_) => {}
+            Err(err) => tracing::warn!(error = %err, "Failed to purge trash"),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use tracing_subscriber::EnvFilter;
+
+/// Filter used when `RUST_LOG` is unset or invalid.
+const DEFAULT_FILTER: &str = "info";
+
+/// Install the global subscriber. `RUST_LOG` picks what is logged and
+/// `LOG_FORMAT` how: `text` (the default) for people, `json` for log
+/// collectors. Read from the environment rather than [`crate::config`] so
+/// that configuration errors can be logged.
+pub fn init() {
+    let (filter, filter_error) = match EnvFilter::try_from_default_env() {
+        Ok(filter) => (filter, None),
+        Err(err) => (EnvFilter::new(DEFAULT_FILTER), std::env::var("RUST_LOG").ok().map(|_| err)),
+    };
+    let format = std::env::var("LOG_FORMAT").unwrap_or_default();
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format.as_str() {
+        "json" => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+        _ => builder.init(),
+    }
+
+    if let Some(err) = filter_error {
+        tracing::warn!(error = %err, "Invalid RUST_LOG, using {:?}", DEFAULT_FILTER);
+    }
+    if !matches!(format.as_str(), "" | "text" | "json") {
+        tracing::warn!(format = %format, "Unknown LOG_FORMAT, using text");
+    }
+}
+
+/// Implement `Debug` showing only the listed fields.
+///
+/// Used instead of `#[derive(Debug)]` on types that hold passwords, tokens
+/// or journal plaintext, so that logging one with `?value` cannot leak
+/// them. Fields added later stay hidden until listed here.
+macro_rules! redacted_debug {
+    ($type:ident { $($field:ident),* $(,)? }) => {
+        impl std::fmt::Debug for $type {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(stringify!($type))
+                    $(.field(stringify!($field), &self.$field))*
+                    .finish_non_exhaustive()
+            }
+        }
+    };
+}
+
+pub(crate) use redacted_debug;
@@ -0,0 +1,176 @@
+use axum::async_trait;
+use lettre::message::{header::ContentType, Mailbox, Message};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use std::path::PathBuf;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::config::{MailConfig, MailTransport};
+use crate::shutdown::BackgroundTasks;
+
+pub const DEFAULT_MAIL_FROM: &str = "Kryptic Journal <no-reply@localhost>";
+
+pub type MailError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A plain-text email to one recipient.
+#[derive(Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers emails to users, out of band of the request that caused them.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailError>;
+}
+
+/// Build the mailer selected by `MAIL_TRANSPORT`.
+pub fn from_config(config: &MailConfig) -> Result<Arc<dyn Mailer>, MailError> {
+    let from = config.from.clone();
+    Ok(match &config.transport {
+        MailTransport::Log => Arc::new(LogMailer { from }),
+        MailTransport::Smtp(url) => Arc::new(SmtpMailer {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build(),
+            from,
+        }),
+        MailTransport::File(dir) => {
+            std::fs::create_dir_all(dir)?;
+            Arc::new(FileMailer { dir: dir.clone(), from })
+        }
+    })
+}
+
+fn build_message(from: &Mailbox, email: Email) -> Result<Message, MailError> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .to(email.to.parse()?)
+        .subject(email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body)?)
+}
+
+/// Writes who each email is for to the log instead of sending it. For
+/// development; bodies, which may hold reset tokens, are never logged.
+pub struct LogMailer {
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        tracing::info!(from = %self.from, to = %email.to, subject = %email.subject, "Email not sent (log mailer)");
+        Ok(())
+    }
+}
+
+/// Sends emails through an SMTP relay.
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        self.transport.send(build_message(&self.from, email)?).await?;
+        Ok(())
+    }
+}
+
+/// Drops each email into a directory as an `.eml` file, for a separate
+/// process to deliver or for inspection.
+pub struct FileMailer {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        let name = format!("{}-{}.eml", OffsetDateTime::now_utc().unix_timestamp(), Uuid::new_v4());
+        tokio::fs::write(self.dir.join(name), message.formatted()).await?;
+        Ok(())
+    }
+}
+
+/// Send `email` without holding up the response, so how long a request
+/// takes does not depend on whether it sent mail. Failures are logged.
+pub fn send_in_background(tasks: &BackgroundTasks, mailer: Arc<dyn Mailer>, email: Email) {
+    tasks.spawn(async move {
+        let subject = email.subject.clone();
+        if let Err(err) = mailer.send(email).await {
+            tracing::error!(error = %err, subject, "Failed to send email");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, PoisonError};
+
+    /// Keeps emails in memory for tests to read back.
+    #[derive(Default)]
+    struct MemoryMailer {
+        sent: Mutex<Vec<Email>>,
+    }
+
+    impl MemoryMailer {
+        fn sent(&self) -> Vec<Email> {
+            self.sent.lock().unwrap_or_else(PoisonError::into_inner).clone()
+        }
+    }
+
+    #[async_trait]
+    impl Mailer for MemoryMailer {
+        async fn send(&self, email: Email) -> Result<(), MailError> {
+            self.sent.lock().unwrap_or_else(PoisonError::into_inner).push(email);
+            Ok(())
+        }
+    }
+
+    fn email(subject: &str) -> Email {
+        Email {
+            to: "someone@example.com".to_string(),
+            subject: subject.to_string(),
+            body: "body".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn background_sends_finish_before_wait_returns() {
+        let mailer = Arc::new(MemoryMailer::default());
+        let tasks = BackgroundTasks::default();
+
+        send_in_background(&tasks, mailer.clone(), email("first"));
+        send_in_background(&tasks, mailer.clone(), email("second"));
+        tasks.wait().await;
+
+        let mut subjects: Vec<String> = mailer.sent().into_iter().map(|email| email.subject).collect();
+        subjects.sort();
+        assert_eq!(subjects, ["first", "second"]);
+    }
+
+    #[test]
+    fn messages_carry_sender_recipient_and_body() {
+        let from: Mailbox = DEFAULT_MAIL_FROM.parse().unwrap();
+        let message = build_message(&from, email("Hello")).unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+
+        assert!(formatted.contains("To: someone@example.com"));
+        assert!(formatted.contains("Subject: Hello"));
+        assert!(formatted.ends_with("body"));
+    }
+
+    #[test]
+    fn invalid_recipients_are_rejected() {
+        let from: Mailbox = DEFAULT_MAIL_FROM.parse().unwrap();
+        let mut email = email("Hello");
+        email.to = "not an address".to_string();
+        assert!(build_message(&from, email).is_err());
+    }
+}
@@ -1,84 +1,251 @@
 use axum::{
-    http::StatusCode,
+    extract::DefaultBodyLimit,
     middleware,
-    response::Json,
     routing::{get, post},
     Router,
 };
 use dotenvy::dotenv;
-use serde_json::{json, Value};
 use sqlx::{postgres::PgPoolOptions, PgPool};
+use std::sync::Arc;
+use std::future::IntoFuture;
 use std::net::SocketAddr;
-use tracing::{info, Level};
-use tracing_subscriber;
+use std::time::Duration;
+use tracing::{error, info, warn};
 
 mod auth;
+mod client_ip;
+mod config;
+mod cors;
 mod db;
+mod error;
+mod jobs;
+mod logging;
+mod mail;
+mod metrics;
+mod quota;
+mod rate_limit;
+mod request_id;
 mod routes;
+mod shutdown;
 mod utils;
+mod validation;
 
 use auth::jwt::auth_middleware;
-use routes::{auth as auth_routes, journal as journal_routes};
+use auth::revocation::RevocationStore;
+use config::Config;
+use mail::Mailer;
+use cors::cors_middleware;
+use rate_limit::{rate_limit_middleware, RateLimiter};
+use request_id::request_id_middleware;
+use shutdown::{BackgroundTasks, Shutdown};
+use utils::encryption::{init_encryption_service, EncryptionService};
+use routes::{
+    auth as auth_routes, health as health_routes, journal as journal_routes, keys as key_routes,
+    metrics as metrics_routes, revisions as revision_routes, search as search_routes,
+    sync as sync_routes, trash as trash_routes,
+};
+
+/// How long background workers, and then the database pool, get to wind
+/// down once requests have drained.
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: PgPool,
+    pub revocations: RevocationStore,
+    pub config: Arc<Config>,
+    pub rate_limiter: RateLimiter,
+    pub mailer: Arc<dyn Mailer>,
+    pub shutdown: Shutdown,
+    /// Work requests hand off to finish after responding, such as sending mail.
+    pub background: BackgroundTasks,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Load environment variables first, so RUST_LOG and LOG_FORMAT can come from .env
+    dotenv().ok();
+
     // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_max_level(Level::INFO)
-        .init();
+    logging::init();
 
-    // Load environment variables
-    dotenv().ok();
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            error!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let database_url = std::env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set in environment");
+    let encryption = EncryptionService::from_keys(&config.encryption.master_keys, config.encryption.strict)?;
+    init_encryption_service(encryption);
+    auth::password::init_dummy_hash();
+    let mailer = match mail::from_config(&config.mail) {
+        Ok(mailer) => mailer,
+        Err(err) => {
+            error!("Invalid mail configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
 
     // Create database connection pool
     let pool = PgPoolOptions::new()
-        .max_connections(20)
-        .connect(&database_url)
+        .max_connections(config.database.max_connections)
+        .min_connections(config.database.min_connections)
+        .acquire_timeout(config.database.acquire_timeout)
+        .connect(&config.database.url)
         .await?;
 
     // Run migrations
-    sqlx::migrate!("./migrations").run(&pool).await?;
+    db::MIGRATOR.run(&pool).await?;
+
+    let shutdown = Shutdown::new();
+    tokio::spawn(shutdown::listen_for_signals(shutdown.clone()));
+
+    // Move any data still on a retired master key or the legacy format
+    let mut workers = vec![tokio::spawn(jobs::reencrypt::run(pool.clone(), shutdown.clone()))];
+
+    let revocations = RevocationStore::new(pool.clone());
+    workers.push(tokio::spawn(revocations.clone().run_purger(shutdown.clone())));
+
+    // Permanently remove entries that have been in the trash too long
+    workers.push(tokio::spawn(jobs::trash::run(
+        pool.clone(),
+        config.limits.trash_retention,
+        shutdown.clone(),
+    )));
+    workers.push(tokio::spawn(jobs::sync::run(pool.clone(), shutdown.clone())));
+    // Index entries written before search existed
+    workers.push(tokio::spawn(jobs::search_index::run(pool.clone(), shutdown.clone())));
+    workers.push(tokio::spawn(auth::throttle::run_purger(
+        pool.clone(),
+        config.auth.login_throttle.clone(),
+        shutdown.clone(),
+    )));
+
+    workers.push(tokio::spawn(auth::password_reset::run_purger(pool.clone(), shutdown.clone())));
+    workers.push(tokio::spawn(auth::registration_notices::run_purger(pool.clone(), shutdown.clone())));
+
+    let rate_limiter = RateLimiter::default();
+    workers.push(tokio::spawn(rate_limiter.clone().run_purger(shutdown.clone())));
 
-    let app_state = AppState { db: pool };
+    let background = BackgroundTasks::default();
+
+    let app_state = AppState {
+        db: pool.clone(),
+        revocations,
+        config: config.clone(),
+        rate_limiter,
+        mailer,
+        shutdown: shutdown.clone(),
+        background: background.clone(),
+    };
 
     // Build our application with routes
     let protected_routes = Router::new()
         .route("/entries", post(journal_routes::create_entry))
         .route("/entries", get(journal_routes::get_entries))
+        .route("/entries/search", get(search_routes::search_entries))
         .route("/entries/:id", get(journal_routes::get_entry))
         .route("/entries/:id", axum::routing::put(journal_routes::update_entry))
+        .route("/entries/:id", axum::routing::patch(journal_routes::patch_entry))
         .route("/entries/:id", axum::routing::delete(journal_routes::delete_entry))
+        .route("/entries/:id/revisions", get(revision_routes::get_revisions))
+        .route("/entries/:id/revisions/:rev", get(revision_routes::get_revision))
+        .route("/entries/:id/revisions/:rev/restore", post(revision_routes::restore_revision))
+        .route("/sync", get(sync_routes::get_changes))
+        .route("/sync", post(sync_routes::push_changes))
+        .route("/trash", get(trash_routes::get_trash))
+        .route("/trash/:id/restore", post(trash_routes::restore_entry))
+        .route("/trash/:id", axum::routing::delete(trash_routes::purge_entry))
+        .route("/keys", get(key_routes::get_key_material))
+        .route("/keys", axum::routing::put(key_routes::put_key_material))
+        .route("/logout", post(auth_routes::logout))
+        .route("/logout-all", post(auth_routes::logout_all))
+        // Runs after auth_middleware, so requests are limited per user
+        .layer(middleware::from_fn_with_state(app_state.clone(), rate_limit_middleware))
         .layer(middleware::from_fn_with_state(app_state.clone(), auth_middleware));
 
-    let app = Router::new()
-        .route("/health", get(health_check))
-        // Auth routes (no middleware)
+    // Unauthenticated, so limited per client IP
+    let public_routes = Router::new()
         .route("/register", post(auth_routes::register))
         .route("/login", post(auth_routes::login))
-        // Merge protected routes
-        .merge(protected_routes)
+        .route("/token/refresh", post(auth_routes::refresh_token))
+        .route("/password/forgot", post(auth_routes::forgot_password))
+        .route("/password/reset", post(auth_routes::reset_password))
+        .layer(middleware::from_fn_with_state(app_state.clone(), rate_limit_middleware));
+
+    let app = Router::new()
+        .route("/health", get(health_routes::live))
+        .route("/health/live", get(health_routes::live))
+        .route("/health/ready", get(health_routes::ready))
+        .merge(public_routes)
+        .merge(protected_routes);
+
+    // /metrics is only ever served on the admin listener, and only when one is configured
+    if let Some(metrics_addr) = config.server.metrics_addr {
+        let admin = Router::new()
+            .route("/metrics", get(metrics_routes::metrics))
+            .with_state(app_state.clone());
+        let admin_listener = tokio::net::TcpListener::bind(metrics_addr).await?;
+        info!("📈 Metrics listening on {}", metrics_addr);
+        let stop = shutdown.clone().triggered();
+        tokio::spawn(async move {
+            let server = axum::serve(admin_listener, admin).with_graceful_shutdown(stop);
+            if let Err(err) = server.await {
+                error!("Metrics server failed: {}", err);
+            }
+        });
+    }
+
+    let app = app
+        .layer(DefaultBodyLimit::max(config.limits.max_body_bytes))
+        .layer(middleware::from_fn_with_state(app_state.clone(), cors_middleware))
+        .layer(middleware::from_fn(metrics::track_requests))
+        .layer(middleware::from_fn(request_id_middleware))
         .with_state(app_state);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let addr = config.server.bind_addr;
     info!("🚀 Kryptic Journal API listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    // Peer addresses are needed to throttle and rate limit per client
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown.clone().triggered())
+        .into_future();
 
-    Ok(())
-}
+    // Once the signal arrives, new connections are refused and readiness
+    // fails; in-flight requests get `shutdown_timeout` to finish
+    let drain_timeout = config.server.shutdown_timeout;
+    let deadline = async {
+        shutdown.clone().triggered().await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        result = server => result?,
+        _ = deadline => warn!(
+            "In-flight requests still running after {:?}; shutting down anyway",
+            drain_timeout
+        ),
+    }
+
+    // Workers stop at their next checkpoint and mail already handed off is
+    // sent; do not wait on them for long
+    let stopped = tokio::time::timeout(CLEANUP_TIMEOUT, async {
+        for worker in workers {
+            let _ = worker.await;
+        }
+        background.wait().await;
+    });
+    if stopped.await.is_err() {
+        warn!("Background workers and tasks did not stop within {:?}", CLEANUP_TIMEOUT);
+    }
 
-async fn health_check() -> Result<Json<Value>, StatusCode> {
-    Ok(Json(json!({
-        "status": "healthy",
-        "service": "kryptic-journal-backend"
-    })))
+    // Waits for connections still held by requests cut off above
+    if tokio::time::timeout(CLEANUP_TIMEOUT, pool.close()).await.is_err() {
+        warn!("Database pool did not close within {:?}", CLEANUP_TIMEOUT);
+    }
+    info!("Shutdown complete");
+
+    Ok(())
 }
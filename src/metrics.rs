@@ -0,0 +1,187 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const HTTP_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Upper bounds, in seconds, of the encryption latency histogram buckets.
+/// AES-GCM on a journal entry takes microseconds, so these are much finer.
+const CRYPTO_BUCKETS: &[f64] = &[0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05];
+
+/// Route label for requests that matched no route, so scanners probing
+/// random paths cannot create unbounded label values.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Cumulative count for each bound in `bounds`.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, seconds: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(&mut self.buckets) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {bucket}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+/// (method, route, status)
+type HttpLabels = (String, String, u16);
+
+/// (operation, result)
+type CryptoLabels = (&'static str, &'static str);
+
+struct Registry {
+    http: BTreeMap<HttpLabels, Histogram>,
+    crypto: BTreeMap<CryptoLabels, Histogram>,
+    logins: BTreeMap<&'static str, u64>,
+    registrations: u64,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    http: BTreeMap::new(),
+    crypto: BTreeMap::new(),
+    logins: BTreeMap::new(),
+    registrations: 0,
+});
+
+// A panic while holding the lock cannot leave the counters inconsistent
+// enough to matter, so keep counting rather than poisoning every request.
+fn registry() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The route pattern a request matched, such as `/entries/:id`.
+pub fn route_label(request: &Request) -> String {
+    request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string())
+}
+
+/// Count each request and time it, labelled by method, matched route and
+/// response status.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = route_label(&request);
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    registry()
+        .http
+        .entry((method, route, response.status().as_u16()))
+        .or_insert_with(|| Histogram::new(HTTP_BUCKETS))
+        .observe(elapsed);
+
+    response
+}
+
+/// Run an encryption `operation`, recording its outcome and duration.
+pub fn time_crypto<T, E>(operation: &'static str, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let started = Instant::now();
+    let result = f();
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    registry()
+        .crypto
+        .entry((operation, outcome))
+        .or_insert_with(|| Histogram::new(CRYPTO_BUCKETS))
+        .observe(elapsed);
+
+    result
+}
+
+pub fn record_login(success: bool) {
+    let outcome = if success { "success" } else { "failure" };
+    *registry().logins.entry(outcome).or_default() += 1;
+}
+
+pub fn record_registration() {
+    registry().registrations += 1;
+}
+
+/// Quote a label value for the text exposition format.
+fn label(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{escaped}\"")
+}
+
+pub fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Render the collected metrics in the Prometheus text exposition format.
+pub fn render(out: &mut String) {
+    let registry = registry();
+
+    write_header(
+        out,
+        "http_request_duration_seconds",
+        "histogram",
+        "Time taken to handle HTTP requests.",
+    );
+    for ((method, route, status), histogram) in &registry.http {
+        let labels = format!("method={},route={},status=\"{status}\"", label(method), label(route));
+        histogram.render(out, "http_request_duration_seconds", &labels);
+    }
+
+    write_header(
+        out,
+        "encryption_operation_duration_seconds",
+        "histogram",
+        "Time taken by encryption and decryption operations.",
+    );
+    for ((operation, outcome), histogram) in &registry.crypto {
+        let labels = format!("operation=\"{operation}\",result=\"{outcome}\"");
+        histogram.render(out, "encryption_operation_duration_seconds", &labels);
+    }
+
+    write_header(out, "auth_logins_total", "counter", "Login attempts.");
+    for outcome in ["success", "failure"] {
+        let count = registry.logins.get(outcome).copied().unwrap_or_default();
+        let _ = writeln!(out, "auth_logins_total{{result=\"{outcome}\"}} {count}");
+    }
+
+    write_header(out, "auth_registrations_total", "counter", "Accounts registered.");
+    let _ = writeln!(out, "auth_registrations_total {}", registry.registrations);
+}
@@ -0,0 +1,127 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::config::LimitsConfig;
+use crate::error::AppError;
+
+pub const DEFAULT_MAX_ENTRIES: i64 = 10_000;
+pub const DEFAULT_MAX_CONTENT_BYTES: i64 = 100 * 1024 * 1024;
+
+/// Bytes an entry or revision row counts towards `users.storage_bytes`:
+/// its title, content and tags as stored. Must agree with [`stored_bytes`].
+pub const STORED_BYTES: &str =
+    "(octet_length(title) + octet_length(content) + COALESCE(octet_length(array_to_string(tags, '')), 0))::BIGINT";
+
+/// Common table expressions that take the bytes of entries deleted in a
+/// `purged` CTE, returning `id`, `user_id` and `bytes` (see [`STORED_BYTES`]),
+/// off their owners' totals along with those of their revisions. The
+/// revisions are still visible here, before the cascade removes them.
+pub fn release_purged() -> String {
+    format!(
+        r#"
+    freed AS (
+        SELECT p.user_id,
+               SUM(p.bytes + COALESCE(
+                   (SELECT SUM({STORED_BYTES}) FROM journal_entry_revisions r WHERE r.entry_id = p.id),
+                   0
+               ))::BIGINT AS bytes
+        FROM purged p
+        GROUP BY p.user_id
+    ),
+    released AS (
+        UPDATE users u SET storage_bytes = u.storage_bytes - freed.bytes
+        FROM freed WHERE u.id = freed.user_id
+    )
+"#
+    )
+}
+
+/// Bytes a row with these stored values counts towards the quota, the same
+/// as [`STORED_BYTES`] computes in SQL.
+pub fn stored_bytes(title: &str, content: &str, tags: Option<&[String]>) -> i64 {
+    let tags: usize = tags.unwrap_or_default().iter().map(String::len).sum();
+    (title.len() + content.len() + tags) as i64
+}
+
+/// Add `delta` bytes, which may be negative, to the user's running total.
+pub async fn add_usage(conn: &mut PgConnection, user_id: Uuid, delta: i64) -> Result<(), sqlx::Error> {
+    if delta != 0 {
+        sqlx::query("UPDATE users SET storage_bytes = storage_bytes + $1 WHERE id = $2")
+            .bind(delta)
+            .bind(user_id)
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Check that storing `new_bytes` (see [`stored_bytes`]) as entry
+/// `entry_id`, new or existing, keeps the user within their entry count and
+/// storage quotas, and record the change in their running total.
+///
+/// Storage covers titles, content and tags as stored, so after encryption,
+/// of entries and their revisions. Trashed entries count until they are
+/// purged. Call this after [`next_change_seq`] has locked the user row, so
+/// concurrent writes cannot both squeeze in, and within the transaction that
+/// writes the entry.
+///
+/// An entry that already exceeds a lowered quota may still be edited, as
+/// long as the edit does not make it larger.
+///
+/// [`next_change_seq`]: crate::routes::sync::next_change_seq
+pub async fn charge(
+    conn: &mut PgConnection,
+    limits: &LimitsConfig,
+    user_id: Uuid,
+    entry_id: Uuid,
+    new_bytes: i64,
+) -> Result<(), AppError> {
+    let current_bytes = sqlx::query_scalar::<_, i64>(&format!(
+        "SELECT {STORED_BYTES} FROM journal_entries WHERE id = $1 AND user_id = $2"
+    ))
+    .bind(entry_id)
+    .bind(user_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    if current_bytes.is_none() {
+        let entries = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM journal_entries WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        if entries >= limits.max_entries {
+            return Err(AppError::QuotaExceeded(format!(
+                "Entry quota of {} reached; permanently delete entries to make room",
+                limits.max_entries
+            )));
+        }
+    }
+
+    let delta = new_bytes - current_bytes.unwrap_or(0);
+    let used = sqlx::query_scalar::<_, i64>("SELECT storage_bytes FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    if delta > 0 && used + delta > limits.max_content_bytes {
+        return Err(AppError::QuotaExceeded(format!(
+            "Storage quota of {} bytes exceeded",
+            limits.max_content_bytes
+        )));
+    }
+
+    add_usage(conn, user_id, delta).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_bytes_counts_title_content_and_tags_in_utf8() {
+        let tags = vec!["café".to_string(), "x".to_string()];
+        assert_eq!(stored_bytes("ab", "cde", Some(&tags)), 2 + 3 + 5 + 1);
+        assert_eq!(stored_bytes("ab", "cde", None), 5);
+        assert_eq!(stored_bytes("", "", Some(&[])), 0);
+    }
+}
@@ -0,0 +1,241 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::auth::jwt::Claims;
+use crate::client_ip::ClientIp;
+use crate::error::AppError;
+use crate::shutdown::Shutdown;
+use crate::AppState;
+
+pub const DEFAULT_AUTH_PER_MINUTE: u32 = 10;
+pub const DEFAULT_AUTH_BURST: u32 = 5;
+pub const DEFAULT_READ_PER_MINUTE: u32 = 300;
+pub const DEFAULT_READ_BURST: u32 = 60;
+pub const DEFAULT_WRITE_PER_MINUTE: u32 = 60;
+pub const DEFAULT_WRITE_BURST: u32 = 20;
+
+/// How often buckets that have refilled completely are dropped.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATELIMIT_RESET: &str = "ratelimit-reset";
+const RATELIMIT_POLICY: &str = "ratelimit-policy";
+
+/// A token bucket: holds up to `burst` requests and refills at `per_minute`.
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    fn per_second(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+/// Routes sharing a limit. Unauthenticated routes are limited per client
+/// IP, the others per user.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum RouteGroup {
+    Auth,
+    Read,
+    Write,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket will have refilled completely.
+    full_at: Instant,
+}
+
+/// Outcome of taking a token, as reported in the `RateLimit-*` headers.
+struct Decision {
+    allowed: bool,
+    limit: RateLimit,
+    remaining: u32,
+    /// Time until the bucket is full again.
+    reset: Duration,
+    /// Time until the next token, when the request was refused.
+    retry_after: Duration,
+}
+
+/// In-process token buckets. Each replica keeps its own, so the effective
+/// limit scales with the number of replicas.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<(RouteGroup, String), Bucket>>>,
+}
+
+impl RateLimiter {
+    fn take(&self, group: RouteGroup, key: String, limit: RateLimit) -> Decision {
+        let now = Instant::now();
+        let rate = limit.per_second();
+        let burst = f64::from(limit.burst);
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = buckets.entry((group, key)).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            full_at: now,
+        });
+
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let reset = Duration::from_secs_f64((burst - bucket.tokens) / rate);
+        bucket.full_at = now + reset;
+
+        Decision {
+            allowed,
+            limit,
+            remaining: bucket.tokens as u32,
+            reset,
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens).max(0.0) / rate),
+        }
+    }
+
+    /// Periodically drop full buckets, which are the same as no bucket.
+    /// Runs until shutdown.
+    pub async fn run_purger(self, shutdown: Shutdown) {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.clone().triggered() => return,
+            }
+            let now = Instant::now();
+            self.buckets
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|_, bucket| bucket.full_at > now);
+        }
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+fn add_headers(headers: &mut HeaderMap, decision: &Decision) {
+    let limit = decision.limit;
+    headers.insert(HeaderName::from_static(RATELIMIT_LIMIT), HeaderValue::from(limit.burst));
+    headers.insert(
+        HeaderName::from_static(RATELIMIT_REMAINING),
+        HeaderValue::from(decision.remaining),
+    );
+    headers.insert(
+        HeaderName::from_static(RATELIMIT_RESET),
+        HeaderValue::from(ceil_secs(decision.reset)),
+    );
+    if let Ok(policy) = HeaderValue::from_str(&format!("{};w=60;burst={}", limit.per_minute, limit.burst)) {
+        headers.insert(HeaderName::from_static(RATELIMIT_POLICY), policy);
+    }
+}
+
+/// Take a token from the caller's bucket for this route group, answering
+/// `429 Too Many Requests` when it is empty. Must run inside
+/// `auth_middleware` on protected routes, so the user is known.
+pub async fn rate_limit_middleware(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limits = &state.config.rate_limit;
+    let (group, key, limit) = match request.extensions().get::<Claims>() {
+        Some(claims) if matches!(*request.method(), Method::GET | Method::HEAD) => {
+            (RouteGroup::Read, claims.sub.clone(), limits.read)
+        }
+        Some(claims) => (RouteGroup::Write, claims.sub.clone(), limits.write),
+        None => (RouteGroup::Auth, ip.to_string(), limits.auth),
+    };
+
+    let decision = state.rate_limiter.take(group, key, limit);
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        AppError::TooManyRequests {
+            message: "Rate limit exceeded; slow down",
+            retry_after: decision.retry_after,
+        }
+        .into_response()
+    };
+    add_headers(response.headers_mut(), &decision);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit { per_minute: 60, burst: 3 };
+
+    #[test]
+    fn allows_a_burst_then_refuses() {
+        let limiter = RateLimiter::default();
+
+        for remaining in [2, 1, 0] {
+            let decision = limiter.take(RouteGroup::Write, "user".to_string(), LIMIT);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+
+        let refused = limiter.take(RouteGroup::Write, "user".to_string(), LIMIT);
+        assert!(!refused.allowed);
+        assert_eq!(refused.remaining, 0);
+        // One token a second, so the next one is at most a second away and a full bucket three
+        assert!(refused.retry_after > Duration::ZERO && refused.retry_after <= Duration::from_secs(1));
+        assert_eq!(ceil_secs(refused.reset), 3);
+    }
+
+    #[test]
+    fn buckets_are_per_group_and_key() {
+        let limiter = RateLimiter::default();
+        for _ in 0..LIMIT.burst {
+            limiter.take(RouteGroup::Write, "user".to_string(), LIMIT);
+        }
+
+        assert!(!limiter.take(RouteGroup::Write, "user".to_string(), LIMIT).allowed);
+        assert!(limiter.take(RouteGroup::Read, "user".to_string(), LIMIT).allowed);
+        assert!(limiter.take(RouteGroup::Write, "other".to_string(), LIMIT).allowed);
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::default();
+        for _ in 0..LIMIT.burst {
+            limiter.take(RouteGroup::Auth, "ip".to_string(), LIMIT);
+        }
+
+        // Pretend the last request was two seconds ago
+        for bucket in limiter.buckets.lock().unwrap().values_mut() {
+            bucket.updated -= Duration::from_secs(2);
+        }
+
+        let decision = limiter.take(RouteGroup::Auth, "ip".to_string(), LIMIT);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 1);
+    }
+
+    #[test]
+    fn ceil_secs_rounds_up() {
+        assert_eq!(ceil_secs(Duration::ZERO), 0);
+        assert_eq!(ceil_secs(Duration::from_millis(1)), 1);
+        assert_eq!(ceil_secs(Duration::from_secs(2)), 2);
+    }
+}
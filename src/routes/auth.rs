@@ -1,24 +1,48 @@
 use axum::{
-    extract::State,
+    extract::{Extension, State},
     http::StatusCode,
-    response::Json,
+    response::{IntoResponse, Json, Response},
 };
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use argon2::password_hash::{rand_core::OsRng, SaltString};
 use serde::Serialize;
+use serde_json::{json, Value};
+use std::net::IpAddr;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
-use crate::db::models::{CreateUser, LoginUser, User};
-use crate::auth::jwt::create_jwt;
+use crate::db::models::{
+    CreateUser, ForgotPasswordRequest, LoginUser, LogoutRequest, RefreshTokenRequest,
+    ResetPasswordRequest, ResetPasswordResponse, User,
+};
+use crate::auth::jwt::{create_jwt, Claims};
+use crate::auth::{password, password_reset, registration_notices};
+use crate::auth::refresh::{
+    issue_refresh_token, revoke_all_refresh_tokens, revoke_refresh_family, rotate_refresh_token,
+};
+use crate::auth::throttle;
+use crate::client_ip::ClientIp;
+use crate::config::{AuthConfig, RegistrationMode};
+use crate::error::AppError;
+use crate::mail::{self, Email};
+use crate::metrics;
+use crate::utils::keys::user_data_key;
+use crate::validation::{check_password, ValidatedJson, ValidationErrors};
 use crate::AppState;
 
 #[derive(Serialize)]
 pub struct AuthResponse {
     pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
     pub user: UserResponse,
 }
 
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
 #[derive(Serialize)]
 pub struct UserResponse {
     pub id: Uuid,
@@ -38,32 +62,23 @@ impl From<User> for UserResponse {
     }
 }
 
+const REGISTRATION_ACCEPTED: &str = "Registration received; check your email for next steps";
+
+/// Register a user. In `open` mode this signs them in; in `conceal` mode it
+/// answers `202 Accepted` whether or not the email or username was taken,
+/// and tells the email's owner what happened by email.
 pub async fn register(
     State(state): State<AppState>,
-    Json(payload): Json<CreateUser>,
-) -> Result<Json<AuthResponse>, StatusCode> {
-    // Check if user already exists
-    let existing_user = sqlx::query_as::<_, User>(
-        "SELECT id, username, email, password_hash, created_at, updated_at FROM users WHERE email = $1"
-    )
-    .bind(&payload.email)
-    .fetch_optional(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    if existing_user.is_some() {
-        return Err(StatusCode::CONFLICT);
-    }
-
-    // Hash password
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(payload.password.as_bytes(), &salt)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .to_string();
+    ValidatedJson(payload): ValidatedJson<CreateUser>,
+) -> Result<Response, AppError> {
+    // Hash and look up the email before trying the insert, so a taken email
+    // runs the same queries and hashing as a new one
+    let password_hash = password::hash_password(&payload.password)?;
+    let email_taken = sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM users WHERE email = $1)")
+        .bind(&payload.email)
+        .fetch_one(&state.db)
+        .await?;
 
-    // Create user
     let user_id = Uuid::new_v4();
     let now = OffsetDateTime::now_utc();
     
@@ -71,6 +86,7 @@ pub async fn register(
         r#"
         INSERT INTO users (id, username, email, password_hash, created_at, updated_at)
         VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT DO NOTHING
         RETURNING id, username, email, password_hash, created_at, updated_at
         "#
     )
@@ -80,48 +96,371 @@ pub async fn register(
     .bind(&password_hash)
     .bind(now)
     .bind(now)
-    .fetch_one(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .fetch_optional(&state.db)
+    .await?;
+
+    let conceal = state.config.auth.registration_mode == RegistrationMode::Conceal;
+
+    let Some(user) = user else {
+        if !conceal {
+            let message = if email_taken {
+                "Email is already registered"
+            } else {
+                "Username is already taken"
+            };
+            return Err(AppError::Conflict(message.to_string()));
+        }
 
-    // Generate JWT
-    let token = create_jwt(user.id)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let email = if email_taken {
+            Email {
+                to: payload.email,
+                subject: "Sign-up attempt with your Kryptic Journal email".to_string(),
+                body: "Someone tried to create a Kryptic Journal account with this email address, \
+                       which already has one. If it was you, log in instead, or reset \
+                       your password if you have forgotten it. Otherwise you can ignore this email."
+                    .to_string(),
+            }
+        } else {
+            Email {
+                to: payload.email,
+                subject: "Your Kryptic Journal sign-up".to_string(),
+                body: format!(
+                    "The username \"{}\" is already taken, so no account was created. \
+                     Please sign up again with a different username.",
+                    payload.username
+                ),
+            }
+        };
+        // Throttled in the background too, so a repeated sign-up answers no slower
+        let (db, mailer) = (state.db.clone(), state.mailer.clone());
+        state.background.spawn(async move {
+            match registration_notices::record_notice(&db, &email.to).await {
+                Ok(true) => {
+                    if let Err(err) = mailer.send(email).await {
+                        tracing::error!(error = %err, "Failed to send registration notice");
+                    }
+                }
+                Ok(false) => tracing::info!("Registration notice throttled; no email sent"),
+                Err(err) => tracing::error!(error = %err, "Failed to record registration notice"),
+            }
+        });
+        return Ok(registration_accepted());
+    };
+
+    metrics::record_registration();
+
+    if conceal {
+        // The data key is created on first use instead, so this path does
+        // the same work as the one for a taken email
+        mail::send_in_background(
+            &state.background,
+            state.mailer.clone(),
+            Email {
+                to: user.email,
+                subject: "Welcome to Kryptic Journal".to_string(),
+                body: format!("Your account \"{}\" is ready. You can now log in.", user.username),
+            },
+        );
+        return Ok(registration_accepted());
+    }
+
+    // Provision the user's data-encryption key up front
+    user_data_key(&state.db, user.id).await?;
+
+    // Generate JWT and start a refresh token family
+    let token = create_jwt(&state.config.auth, user.id)?;
+    let refresh_token =
+        issue_refresh_token(&state.db, user.id, state.config.auth.refresh_token_ttl).await?;
 
     Ok(Json(AuthResponse {
         token,
+        refresh_token,
+        expires_in: state.config.auth.access_token_ttl.whole_seconds(),
         user: user.into(),
-    }))
+    })
+    .into_response())
+}
+
+fn registration_accepted() -> Response {
+    (StatusCode::ACCEPTED, Json(json!({ "message": REGISTRATION_ACCEPTED }))).into_response()
+}
+
+/// Count a failed login towards the account and IP lockouts.
+async fn login_failed(state: &AppState, email: &str, ip: IpAddr) -> AppError {
+    metrics::record_login(false);
+    match throttle::record_failure(&state.db, &state.config.auth.login_throttle, email, ip).await {
+        Ok(()) => AppError::InvalidCredentials,
+        Err(err) => err.into(),
+    }
 }
 
 pub async fn login(
     State(state): State<AppState>,
-    Json(payload): Json<LoginUser>,
-) -> Result<Json<AuthResponse>, StatusCode> {
+    ClientIp(ip): ClientIp,
+    ValidatedJson(payload): ValidatedJson<LoginUser>,
+) -> Result<Json<AuthResponse>, AppError> {
+    // Locked out accounts and IPs are turned away before any password is checked
+    if let Some(retry_after) = throttle::lockout_remaining(&state.db, &payload.email, ip).await? {
+        return Err(AppError::TooManyRequests {
+            message: "Too many failed login attempts; try again later",
+            retry_after,
+        });
+    }
+
     // Find user by email
     let user = sqlx::query_as::<_, User>(
         "SELECT id, username, email, password_hash, created_at, updated_at FROM users WHERE email = $1"
     )
     .bind(&payload.email)
     .fetch_optional(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .ok_or(StatusCode::UNAUTHORIZED)?;
+    .await?;
+    let Some(user) = user else {
+        // Check a password anyway, so unknown emails answer no faster
+        password::verify_dummy(&payload.password);
+        return Err(login_failed(&state, &payload.email, ip).await);
+    };
 
     // Verify password
-    let parsed_hash = PasswordHash::new(&user.password_hash)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    Argon2::default()
-        .verify_password(payload.password.as_bytes(), &parsed_hash)
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    if !password::verify_password(&payload.password, &user.password_hash)? {
+        return Err(login_failed(&state, &payload.email, ip).await);
+    }
+    metrics::record_login(true);
+    throttle::record_success(&state.db, &payload.email).await?;
 
-    // Generate JWT
-    let token = create_jwt(user.id)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Generate JWT and start a refresh token family
+    let token = create_jwt(&state.config.auth, user.id)?;
+    let refresh_token =
+        issue_refresh_token(&state.db, user.id, state.config.auth.refresh_token_ttl).await?;
 
     Ok(Json(AuthResponse {
         token,
+        refresh_token,
+        expires_in: state.config.auth.access_token_ttl.whole_seconds(),
         user: user.into(),
     }))
-} 
\ No newline at end of file
+} 
+
+pub async fn refresh_token(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshTokenRequest>,
+) -> Result<Json<TokenResponse>, AppError> {
+    let (user_id, refresh_token) = rotate_refresh_token(
+        &state.db,
+        &payload.refresh_token,
+        state.config.auth.refresh_token_ttl,
+    )
+    .await?;
+
+    let token = create_jwt(&state.config.auth, user_id)?;
+
+    Ok(Json(TokenResponse {
+        token,
+        refresh_token,
+        expires_in: state.config.auth.access_token_ttl.whole_seconds(),
+    }))
+}
+
+pub async fn logout(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    payload: Option<Json<LogoutRequest>>,
+) -> Result<Json<Value>, AppError> {
+    let user_uuid = Uuid::parse_str(&claims.sub).map_err(|_| AppError::Unauthorized)?;
+
+    state
+        .revocations
+        .revoke(user_uuid, &claims)
+        .await?;
+
+    // Also end the refresh token family of this session if the client sent it
+    if let Some(refresh_token) = payload.and_then(|Json(p)| p.refresh_token) {
+        revoke_refresh_family(&state.db, user_uuid, &refresh_token).await?;
+    }
+
+    Ok(Json(json!({
+        "message": "Logged out successfully"
+    })))
+}
+
+pub async fn logout_all(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Value>, AppError> {
+    let user_uuid = Uuid::parse_str(&claims.sub).map_err(|_| AppError::Unauthorized)?;
+
+    state
+        .revocations
+        .revoke_all(user_uuid)
+        .await?;
+
+    revoke_all_refresh_tokens(&state.db, user_uuid).await?;
+
+    Ok(Json(json!({
+        "message": "Logged out of all sessions"
+    })))
+}
+const RESET_REQUESTED: &str = "If an account uses this email, a password reset link has been sent";
+const KEY_MATERIAL_SERVER: &str = "server";
+const KEY_MATERIAL_REPLACED: &str = "replaced";
+const KEY_MATERIAL_UNCHANGED: &str = "unchanged";
+
+fn reset_email(config: &AuthConfig, to: String, token: &str, client_encrypted: bool) -> Email {
+    let minutes = config.password_reset_ttl.whole_minutes();
+    let mut body = match &config.password_reset_url {
+        Some(url) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            format!(
+                "Someone asked to reset the password of your Kryptic Journal account. To choose a \
+                 new password, open this link within {minutes} minutes:\n\n{url}{separator}token={token}\n"
+            )
+        }
+        None => format!(
+            "Someone asked to reset the password of your Kryptic Journal account. To choose a \
+             new password, use this reset token within {minutes} minutes:\n\n{token}\n"
+        ),
+    };
+    body.push_str("\nIf you did not ask for this, ignore this email; your password stays the same.\n");
+    if client_encrypted {
+        body.push_str(
+            "\nYour entries are encrypted with a key only your devices hold. Resetting your \
+             password does not change that key: you will still need your old password or \
+             recovery key to read them.\n",
+        );
+    }
+
+    Email {
+        to,
+        subject: "Reset your Kryptic Journal password".to_string(),
+        body,
+    }
+}
+
+/// Email the user a single-use password reset token. Answers the same
+/// whether or not the email has an account.
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    ValidatedJson(payload): ValidatedJson<ForgotPasswordRequest>,
+) -> Result<Response, AppError> {
+    let user = sqlx::query_as::<_, (Uuid, bool)>(
+        "SELECT id, client_encrypted FROM users WHERE email = $1"
+    )
+    .bind(&payload.email)
+    .fetch_optional(&state.db)
+    .await?;
+
+    if let Some((user_id, client_encrypted)) = user {
+        // Issued in the background too, so a known email answers no slower
+        let (db, config, mailer) = (state.db.clone(), state.config.clone(), state.mailer.clone());
+        state.background.spawn(async move {
+            let auth = &config.auth;
+            match password_reset::issue_reset_token(&db, user_id, auth.password_reset_ttl).await {
+                Ok(Some(token)) => {
+                    let email = reset_email(auth, payload.email, &token, client_encrypted);
+                    if let Err(err) = mailer.send(email).await {
+                        tracing::error!(error = %err, %user_id, "Failed to send password reset email");
+                    }
+                }
+                Ok(None) => tracing::info!(%user_id, "Password reset throttled; no email sent"),
+                Err(err) => tracing::error!(error = %err, %user_id, "Failed to issue password reset token"),
+            }
+        });
+    }
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "message": RESET_REQUESTED }))).into_response())
+}
+
+/// Set a new password with a reset token and sign the user out everywhere.
+///
+/// Server-encrypted entries are protected by a key the server holds, which
+/// the password never touched. Client-encrypted accounts keep their key
+/// material unless the client sends it re-wrapped under the new password.
+pub async fn reset_password(
+    State(state): State<AppState>,
+    ValidatedJson(payload): ValidatedJson<ResetPasswordRequest>,
+) -> Result<Json<ResetPasswordResponse>, AppError> {
+    // Hash first, so the user row is not locked while hashing
+    let password_hash = password::hash_password(&payload.password)?;
+
+    let mut tx = state.db.begin().await?;
+
+    let user_id = password_reset::consume_reset_token(&mut tx, &payload.token)
+        .await?
+        .ok_or(AppError::InvalidResetToken)?;
+
+    let (username, email, client_encrypted) = sqlx::query_as::<_, (String, String, bool)>(
+        "SELECT username, email, client_encrypted FROM users WHERE id = $1 FOR UPDATE"
+    )
+    .bind(user_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let mut errors = ValidationErrors::default();
+    let local_part = email.split('@').next().unwrap_or_default();
+    check_password(&mut errors, &payload.password, &[&username, local_part]);
+    errors.into_result()?;
+
+    let now = OffsetDateTime::now_utc();
+
+    let key_material = match (client_encrypted, &payload.key_material) {
+        (false, Some(_)) => {
+            return Err(AppError::BadRequest(
+                "`key_material` only applies to client-encrypted accounts".to_string(),
+            ))
+        }
+        (false, None) => KEY_MATERIAL_SERVER,
+        (true, Some(material)) => {
+            sqlx::query(
+                r#"
+                INSERT INTO client_key_material (user_id, wrapped_key, kdf, created_at, updated_at)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (user_id) DO UPDATE SET wrapped_key = $2, kdf = $3, updated_at = $5
+                "#
+            )
+            .bind(user_id)
+            .bind(&material.wrapped_key)
+            .bind(sqlx::types::Json(&material.kdf))
+            .bind(now)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+            KEY_MATERIAL_REPLACED
+        }
+        (true, None) => KEY_MATERIAL_UNCHANGED,
+    };
+
+    // A new password ends every existing session
+    sqlx::query(
+        "UPDATE users SET password_hash = $1, tokens_valid_after = $2, updated_at = $2 WHERE id = $3"
+    )
+    .bind(&password_hash)
+    .bind(now)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+    revoke_all_refresh_tokens(&mut *tx, user_id).await?;
+
+    tx.commit().await?;
+    state.revocations.cache_revoke_all(user_id, now);
+
+    // The reset proves ownership, so lift any lockout from the forgotten password
+    if let Err(err) = throttle::record_success(&state.db, &email).await {
+        tracing::warn!(error = %err, %user_id, "Failed to clear login failures after password reset");
+    }
+
+    mail::send_in_background(
+        &state.background,
+        state.mailer.clone(),
+        Email {
+            to: email,
+            subject: "Your Kryptic Journal password was changed".to_string(),
+            body: "The password of your Kryptic Journal account was just reset, and every device \
+                   was signed out. If you did not do this, reset your password again right away."
+                .to_string(),
+        },
+    );
+
+    Ok(Json(ResetPasswordResponse {
+        message: "Password has been reset; log in with the new password",
+        key_material,
+    }))
+}
@@ -0,0 +1,174 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::db::MIGRATOR;
+use crate::utils::encryption::{get_encryption_service, DataKey, EncryptionError};
+use crate::AppState;
+
+/// How long a single readiness check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_SHA: Option<&str> = option_env!("GIT_SHA"); // Set by the Docker build
+
+#[derive(Serialize)]
+pub struct ComponentStatus {
+    pub status: &'static str, // up or down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessChecks {
+    pub database: ComponentStatus,
+    pub migrations: ComponentStatus,
+    pub encryption: ComponentStatus,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub status: &'static str, // ready, not_ready or shutting_down
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub checks: ReadinessChecks,
+}
+
+impl ComponentStatus {
+    fn from_result(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Self { status: "up", error: None },
+            Err(error) => Self { status: "down", error: Some(error) },
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Run a check, failing it if it does not finish within [`CHECK_TIMEOUT`].
+async fn timed<F>(check: F) -> ComponentStatus
+where
+    F: std::future::Future<Output = Result<(), String>>,
+{
+    let result = tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_string()));
+    ComponentStatus::from_result(result)
+}
+
+// Failures are logged in full; the unauthenticated response only names them
+fn failed(check: &str, err: impl std::fmt::Display, message: &str) -> String {
+    tracing::warn!(check, error = %err, "Readiness check failed");
+    message.to_string()
+}
+
+async fn check_database(db: &PgPool) -> Result<(), String> {
+    sqlx::query("SELECT 1")
+        .execute(db)
+        .await
+        .map(|_| ())
+        .map_err(|err| failed("database", err, "unreachable"))
+}
+
+/// Every migration compiled into the binary has been applied successfully.
+async fn check_migrations(db: &PgPool) -> Result<(), String> {
+    let applied: HashSet<i64> = sqlx::query_scalar::<_, i64>(
+        "SELECT version FROM _sqlx_migrations WHERE success"
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|err| failed("migrations", err, "cannot read migration history"))?
+    .into_iter()
+    .collect();
+
+    let pending: Vec<String> = MIGRATOR
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| migration.version.to_string())
+        .collect();
+
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("pending migrations: {}", pending.join(", ")))
+    }
+}
+
+/// The master keys can wrap and unwrap a data key, and open a key already
+/// stored in the database.
+async fn check_encryption(db: &PgPool) -> Result<(), String> {
+    let service = get_encryption_service();
+
+    let round_trip = || -> Result<(), EncryptionError> {
+        let wrapped = service.wrap_key(&DataKey::generate(service.is_strict())?)?;
+        service.unwrap_key(&wrapped)?;
+        Ok(())
+    };
+    round_trip().map_err(|err| failed("encryption", err, "master key cannot wrap keys"))?;
+
+    let stored = sqlx::query_scalar::<_, String>("SELECT wrapped_key FROM user_keys LIMIT 1")
+        .fetch_optional(db)
+        .await
+        .map_err(|err| failed("encryption", err, "cannot read stored keys"))?;
+    if let Some(wrapped) = stored {
+        service
+            .unwrap_key(&wrapped)
+            .map_err(|err| failed("encryption", err, "master keys cannot open stored user keys"))?;
+    }
+
+    Ok(())
+}
+
+/// Liveness: the process is up and serving requests. Does not touch the
+/// database, so an outage does not get the process restarted.
+pub async fn live() -> Json<Value> {
+    Json(json!({
+        "status": "alive",
+        "service": "kryptic-journal-backend",
+        "version": VERSION,
+    }))
+}
+
+/// Readiness: the database is reachable and fully migrated and the master
+/// keys work. Answers `503 Service Unavailable` if any check fails, and as
+/// soon as shutdown starts so that no new traffic is routed here.
+pub async fn ready(State(state): State<AppState>) -> Response {
+    let (database, migrations, encryption) = tokio::join!(
+        timed(check_database(&state.db)),
+        timed(check_migrations(&state.db)),
+        timed(check_encryption(&state.db)),
+    );
+    let checks = ReadinessChecks {
+        database,
+        migrations,
+        encryption,
+    };
+
+    let shutting_down = state.shutdown.is_triggered();
+    let is_ready = !shutting_down
+        && checks.database.is_up()
+        && checks.migrations.is_up()
+        && checks.encryption.is_up();
+    let status = if is_ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = ReadinessResponse {
+        status: match (is_ready, shutting_down) {
+            (_, true) => "shutting_down",
+            (true, false) => "ready",
+            (false, false) => "not_ready",
+        },
+        version: VERSION,
+        git_sha: GIT_SHA.unwrap_or("unknown"),
+        checks,
+    };
+
+    (status, Json(body)).into_response()
+}
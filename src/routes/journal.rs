@@ -1,222 +1,742 @@
 use axum::{
-    extract::{Extension, Path, State},
-    http::StatusCode,
-    response::Json,
+    extract::{Extension, Path, Query, State},
+    http::{
+        header::{ETAG, IF_MATCH, IF_NONE_MATCH},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Json, Response},
 };
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
+use sqlx::{PgConnection, Postgres, QueryBuilder};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
-use crate::db::models::{CreateJournalEntry, JournalEntry, JournalEntryResponse, UpdateJournalEntry};
-use crate::utils::encryption::{encrypt_text, decrypt_text};
+use crate::config::LimitsConfig;
+use crate::db::models::{
+    CreateJournalEntry, EntryDocument, EntryFilter, JournalEntry, JournalEntryPage,
+    JournalEntryResponse, UpdateJournalEntry,
+};
+use crate::error::AppError;
+use crate::quota;
+use crate::routes::revisions::record_revision;
+use crate::routes::sync::next_change_seq;
+use crate::utils::keys::{entry_cipher, EntryCipher};
+use crate::utils::search::index_entry;
+use crate::utils::sealing::{
+    open_content, open_tags, open_title, seal_content, seal_metadata, tag_blind_index,
+};
+use crate::validation::{Validate, ValidatedJson};
 use crate::AppState;
 
-pub async fn create_entry(
-    State(state): State<AppState>,
-    Extension(user_id): Extension<String>,
-    Json(payload): Json<CreateJournalEntry>,
-) -> Result<Json<JournalEntryResponse>, StatusCode> {
-    let user_uuid = Uuid::parse_str(&user_id)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+pub const ENTRY_COLUMNS: &str =
+    "id, user_id, title, content, mood_score, tags, client_kdf, metadata_encrypted, tags_position_bound, created_at, updated_at, deleted_at, version";
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// Title, tags and tag blind index in the form they are written to the database.
+pub struct StoredMetadata {
+    pub title: String,
+    pub tags: Option<Vec<String>>,
+    pub tag_index: Option<Vec<String>>,
+    pub encrypted: bool,
+}
+
+/// Encode the position after `entry` as an opaque pagination cursor.
+fn encode_cursor(entry: &JournalEntry) -> String {
+    hex::encode(format!("{}:{}", entry.created_at.unix_timestamp_nanos(), entry.id))
+}
+
+fn decode_cursor(cursor: &str) -> Option<(OffsetDateTime, Uuid)> {
+    let decoded = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+    let (nanos, id) = decoded.split_once(':')?;
+    let created_at = OffsetDateTime::from_unix_timestamp_nanos(nanos.parse().ok()?).ok()?;
+    Some((created_at, Uuid::parse_str(id).ok()?))
+}
+
+/// ETag of an entry version.
+fn entry_etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Whether an `If-Match` or `If-None-Match` header lists `etag`. `If-Match`
+/// needs a strong match, so weak (`W/`) tags only count when `weak` is set.
+fn etag_matches(header: &HeaderValue, etag: &str, weak: bool) -> bool {
+    let Ok(header) = header.to_str() else {
+        return false;
+    };
+
+    header.split(',').map(str::trim).any(|tag| {
+        tag == "*" || tag == etag || (weak && tag.strip_prefix("W/") == Some(etag))
+    })
+}
+
+/// Whether an update based on `If-Match` or the given `version` would
+/// overwrite a newer version than the client has seen.
+fn is_stale(headers: &HeaderMap, version: Option<i64>, current_version: i64) -> bool {
+    match (headers.get(IF_MATCH), version) {
+        (Some(if_match), _) => !etag_matches(if_match, &entry_etag(current_version), false),
+        (None, Some(version)) => version != current_version,
+        (None, None) => false,
+    }
+}
+
+/// Apply a JSON Merge Patch (RFC 7396) to `target`.
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// Merge `patch` into `document` and validate the result. Removing a
+/// required field, like `{"title": null}`, fails validation.
+fn apply_patch(document: EntryDocument, patch: &Value) -> Result<EntryDocument, AppError> {
+    let mut document = serde_json::to_value(document).map_err(|err| AppError::Internal(err.to_string()))?;
+    merge_patch(&mut document, patch);
+
+    let document: EntryDocument = serde_json::from_value(document)
+        .map_err(|err| AppError::BadRequest(format!("Patched entry is invalid: {err}")))?;
+    document.validate()?;
+    Ok(document)
+}
+
+fn entry_with_etag(status: StatusCode, entry: JournalEntryResponse) -> Response {
+    (status, [(ETAG, entry_etag(entry.version))], Json(entry)).into_response()
+}
+
+fn kdf_not_allowed() -> AppError {
+    AppError::BadRequest("`kdf` is only accepted for client-encrypted entries".to_string())
+}
+
+/// Produce the stored form of an entry's content.
+///
+/// Server-encrypted users must send plaintext `content`; client-encrypted
+/// users must send `ciphertext`, which is stored as-is. Plaintext is never
+/// accepted for a client-encrypted user.
+pub fn store_content(
+    cipher: &EntryCipher,
+    user_id: Uuid,
+    entry_id: Uuid,
+    content: Option<&str>,
+    ciphertext: Option<&str>,
+) -> Result<String, AppError> {
+    match (cipher, content, ciphertext) {
+        (EntryCipher::Server(data_key), Some(content), None) => {
+            Ok(seal_content(data_key, user_id, entry_id, content)?)
+        }
+        (EntryCipher::Client, None, Some(ciphertext)) => Ok(ciphertext.to_string()),
+        (EntryCipher::Server(_), _, _) => Err(AppError::BadRequest(
+            "Server-encrypted entries take `content`, not `ciphertext`".to_string(),
+        )),
+        (EntryCipher::Client, _, _) => Err(AppError::BadRequest(
+            "Client-encrypted entries take `ciphertext`, not `content`".to_string(),
+        )),
+    }
+}
+
+/// Produce the stored form of an entry's title and tags. Client-encrypted
+/// users are responsible for protecting these themselves.
+pub fn store_metadata(
+    cipher: &EntryCipher,
+    user_id: Uuid,
+    entry_id: Uuid,
+    title: &str,
+    tags: Option<&[String]>,
+) -> Result<StoredMetadata, AppError> {
+    match cipher {
+        EntryCipher::Server(data_key) => {
+            let sealed = seal_metadata(data_key, user_id, entry_id, title, tags)?;
+            Ok(StoredMetadata {
+                title: sealed.title,
+                tags: sealed.tags,
+                tag_index: sealed.tag_index,
+                encrypted: true,
+            })
+        }
+        EntryCipher::Client => Ok(StoredMetadata {
+            title: title.to_string(),
+            tags: tags.map(<[String]>::to_vec),
+            tag_index: None,
+            encrypted: false,
+        }),
+    }
+}
+
+/// Plaintext title and tags of a stored entry.
+fn open_metadata(
+    cipher: &EntryCipher,
+    entry: &JournalEntry,
+) -> Result<(String, Option<Vec<String>>), AppError> {
+    match cipher {
+        EntryCipher::Server(data_key) if entry.metadata_encrypted => {
+            let title = open_title(data_key, entry.user_id, entry.id, &entry.title)?;
+            let tags = entry
+                .tags
+                .as_deref()
+                .map(|tags| open_tags(data_key, entry.user_id, entry.id, tags, entry.tags_position_bound))
+                .transpose()?;
+            Ok((title, tags))
+        }
+        _ => Ok((entry.title.clone(), entry.tags.clone())),
+    }
+}
+
+pub fn entry_response(cipher: &EntryCipher, entry: JournalEntry) -> Result<JournalEntryResponse, AppError> {
+    let (title, tags) = open_metadata(cipher, &entry)?;
 
-    // Encrypt content
-    let encrypted_content = encrypt_text(&payload.content)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (content, ciphertext, kdf) = match cipher {
+        EntryCipher::Server(data_key) => {
+            // Decrypt content for response
+            let content = open_content(data_key, entry.user_id, entry.id, &entry.content)?;
+            (Some(content), None, None)
+        }
+        EntryCipher::Client => (None, Some(entry.content), entry.client_kdf.map(|kdf| kdf.0)),
+    };
 
-    let entry_id = Uuid::new_v4();
+    Ok(JournalEntryResponse {
+        id: entry.id,
+        title,
+        content,
+        ciphertext,
+        kdf,
+        mood_score: entry.mood_score,
+        tags,
+        created_at: entry.created_at,
+        updated_at: entry.updated_at,
+        deleted_at: entry.deleted_at,
+        version: entry.version,
+    })
+}
+
+/// Load an entry for writing, locking its row until the transaction ends.
+/// Entries in the trash are not found.
+pub async fn lock_entry(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    entry_id: Uuid,
+) -> Result<JournalEntry, AppError> {
+    sqlx::query_as::<_, JournalEntry>(&format!(
+        "SELECT {ENTRY_COLUMNS} FROM journal_entries WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL FOR UPDATE"
+    ))
+    .bind(entry_id)
+    .bind(user_id)
+    .fetch_optional(conn)
+    .await?
+    .ok_or(AppError::NotFound("Entry"))
+}
+
+/// Encrypt, store and index a new entry under `entry_id`. Fails with
+/// `409 Conflict` if that ID is already taken, or `403 Forbidden` if the
+/// user's quota is used up.
+pub async fn insert_entry(
+    conn: &mut PgConnection,
+    cipher: &EntryCipher,
+    limits: &LimitsConfig,
+    user_id: Uuid,
+    entry_id: Uuid,
+    payload: &CreateJournalEntry,
+) -> Result<JournalEntry, AppError> {
+    // Encrypt content, title and tags, bound to this entry
+    let stored_content = store_content(
+        cipher,
+        user_id,
+        entry_id,
+        payload.content.as_deref(),
+        payload.ciphertext.as_deref(),
+    )?;
+    let metadata = store_metadata(cipher, user_id, entry_id, &payload.title, payload.tags.as_deref())?;
+
+    let client_kdf = match cipher {
+        EntryCipher::Client => payload.kdf.clone().map(sqlx::types::Json),
+        EntryCipher::Server(_) if payload.kdf.is_some() => return Err(kdf_not_allowed()),
+        EntryCipher::Server(_) => None,
+    };
+
+    let change_seq = next_change_seq(conn, user_id).await?;
+    let stored_bytes = quota::stored_bytes(&metadata.title, &stored_content, metadata.tags.as_deref());
+    quota::charge(conn, limits, user_id, entry_id, stored_bytes).await?;
     let now = OffsetDateTime::now_utc();
 
-    let entry = sqlx::query_as::<_, JournalEntry>(
+    let entry = sqlx::query_as::<_, JournalEntry>(&format!(
         r#"
-        INSERT INTO journal_entries (id, user_id, title, content, mood_score, tags, created_at, updated_at)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-        RETURNING id, user_id, title, content, mood_score, tags, created_at, updated_at
+        INSERT INTO journal_entries (id, user_id, title, content, mood_score, tags, tag_index, metadata_encrypted, tags_position_bound, client_kdf, change_seq, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $8, $9, $10, $11, $12)
+        ON CONFLICT (id) DO NOTHING
+        RETURNING {ENTRY_COLUMNS}
         "#
-    )
+    ))
     .bind(entry_id)
-    .bind(user_uuid)
-    .bind(&payload.title)
-    .bind(&encrypted_content)
+    .bind(user_id)
+    .bind(&metadata.title)
+    .bind(&stored_content)
     .bind(payload.mood_score)
-    .bind(&payload.tags)
+    .bind(&metadata.tags)
+    .bind(&metadata.tag_index)
+    .bind(metadata.encrypted)
+    .bind(client_kdf)
+    .bind(change_seq)
     .bind(now)
     .bind(now)
-    .fetch_one(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| AppError::Conflict("An entry with this ID already exists".to_string()))?;
 
-    // Decrypt content for response
-    let decrypted_content = decrypt_text(&entry.content)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let (EntryCipher::Server(data_key), Some(content)) = (cipher, payload.content.as_deref()) {
+        index_entry(conn, data_key, user_id, entry_id, &payload.title, content).await?;
+    }
 
-    Ok(Json(JournalEntryResponse {
-        id: entry.id,
-        title: entry.title,
-        content: decrypted_content,
-        mood_score: entry.mood_score,
-        tags: entry.tags,
-        created_at: entry.created_at,
-        updated_at: entry.updated_at,
-    }))
+    Ok(entry)
 }
 
+/// Apply `payload` to an entry locked with [`lock_entry`]. Fields left out
+/// of the payload keep their current value. Version checks are up to the
+/// caller.
+pub async fn apply_update(
+    conn: &mut PgConnection,
+    cipher: &EntryCipher,
+    limits: &LimitsConfig,
+    existing_entry: JournalEntry,
+    payload: &UpdateJournalEntry,
+) -> Result<JournalEntry, AppError> {
+    let user_id = existing_entry.user_id;
+    let current = entry_response(cipher, existing_entry)?;
+
+    // New content or ciphertext replaces the old one
+    let (content, ciphertext) = if payload.content.is_some() || payload.ciphertext.is_some() {
+        (payload.content.clone(), payload.ciphertext.clone())
+    } else {
+        (current.content.clone(), current.ciphertext.clone())
+    };
+
+    let document = EntryDocument {
+        title: payload.title.clone().unwrap_or_else(|| current.title.clone()),
+        content,
+        ciphertext,
+        kdf: payload.kdf.clone().or_else(|| current.kdf.clone()),
+        mood_score: payload.mood_score.or(current.mood_score),
+        tags: payload.tags.clone().or_else(|| current.tags.clone()),
+    };
+
+    replace_entry(conn, cipher, limits, user_id, &current, &document).await
+}
+
+/// Overwrite an entry locked with [`lock_entry`] with `document`, keeping
+/// the version it replaces as a revision. `current` is the entry as it is
+/// now, decrypted.
+pub async fn replace_entry(
+    conn: &mut PgConnection,
+    cipher: &EntryCipher,
+    limits: &LimitsConfig,
+    user_id: Uuid,
+    current: &JournalEntryResponse,
+    document: &EntryDocument,
+) -> Result<JournalEntry, AppError> {
+    let entry_id = current.id;
+
+    let stored_content = store_content(
+        cipher,
+        user_id,
+        entry_id,
+        document.content.as_deref(),
+        document.ciphertext.as_deref(),
+    )?;
+    let metadata = store_metadata(cipher, user_id, entry_id, &document.title, document.tags.as_deref())?;
+
+    let client_kdf = match cipher {
+        EntryCipher::Client => document.kdf.clone().map(sqlx::types::Json),
+        EntryCipher::Server(_) if document.kdf.is_some() => return Err(kdf_not_allowed()),
+        EntryCipher::Server(_) => None,
+    };
+
+    // Keep the version being overwritten
+    record_revision(conn, entry_id, limits.revision_retention).await?;
+
+    let change_seq = next_change_seq(conn, user_id).await?;
+    let stored_bytes = quota::stored_bytes(&metadata.title, &stored_content, metadata.tags.as_deref());
+    quota::charge(conn, limits, user_id, entry_id, stored_bytes).await?;
+    let now = OffsetDateTime::now_utc();
+
+    let updated_entry = sqlx::query_as::<_, JournalEntry>(&format!(
+        r#"
+        UPDATE journal_entries
+        SET title = $1, content = $2, mood_score = $3, tags = $4, tag_index = $5,
+            metadata_encrypted = $6, tags_position_bound = $6, client_kdf = $7, updated_at = $8, version = version + 1,
+            change_seq = $9
+        WHERE id = $10
+        RETURNING {ENTRY_COLUMNS}
+        "#
+    ))
+    .bind(&metadata.title)
+    .bind(&stored_content)
+    .bind(document.mood_score)
+    .bind(&metadata.tags)
+    .bind(&metadata.tag_index)
+    .bind(metadata.encrypted)
+    .bind(client_kdf)
+    .bind(now)
+    .bind(change_seq)
+    .bind(entry_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    // Re-index when the searchable text changes
+    if let (EntryCipher::Server(data_key), Some(content)) = (cipher, document.content.as_deref()) {
+        if document.title != current.title || current.content.as_deref() != Some(content) {
+            index_entry(conn, data_key, user_id, entry_id, &document.title, content).await?;
+        }
+    }
+
+    Ok(updated_entry)
+}
+
+/// Move an entry locked with [`lock_entry`] to the trash.
+pub async fn trash_entry(conn: &mut PgConnection, entry: &JournalEntry) -> Result<(), AppError> {
+    let change_seq = next_change_seq(conn, entry.user_id).await?;
+
+    sqlx::query(
+        "UPDATE journal_entries SET deleted_at = $1, version = version + 1, change_seq = $2 WHERE id = $3"
+    )
+    .bind(OffsetDateTime::now_utc())
+    .bind(change_seq)
+    .bind(entry.id)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn create_entry(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    ValidatedJson(payload): ValidatedJson<CreateJournalEntry>,
+) -> Result<Json<JournalEntryResponse>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    let entry = insert_entry(&mut tx, &cipher, &state.config.limits, user_uuid, Uuid::new_v4(), &payload).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(entry_response(&cipher, entry)?))
+}
+
+/// List entries newest first, one page at a time.
+///
+/// Pages are keyset-paginated on `(created_at, id)`, so they stay stable
+/// while entries are added and only one page is ever decrypted.
 pub async fn get_entries(
     State(state): State<AppState>,
     Extension(user_id): Extension<String>,
-) -> Result<Json<Vec<JournalEntryResponse>>, StatusCode> {
-    let user_uuid = Uuid::parse_str(&user_id)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Query(filter): Query<EntryFilter>,
+) -> Result<Json<JournalEntryPage>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
 
-    let entries = sqlx::query_as::<_, JournalEntry>(
-        "SELECT id, user_id, title, content, mood_score, tags, created_at, updated_at FROM journal_entries WHERE user_id = $1 ORDER BY created_at DESC"
-    )
-    .bind(user_uuid)
-    .fetch_all(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(AppError::BadRequest(format!("`limit` must be between 1 and {MAX_PAGE_SIZE}")));
+    }
+    if let (Some(min), Some(max)) = (filter.mood_min, filter.mood_max) {
+        if min > max {
+            return Err(AppError::BadRequest("`mood_min` must not exceed `mood_max`".to_string()));
+        }
+    }
+    let cursor = filter
+        .cursor
+        .as_deref()
+        .map(|cursor| decode_cursor(cursor).ok_or_else(|| AppError::BadRequest("Invalid cursor".to_string())))
+        .transpose()?;
+
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let mut query = QueryBuilder::<Postgres>::new(format!(
+        "SELECT {ENTRY_COLUMNS} FROM journal_entries WHERE deleted_at IS NULL AND user_id = "
+    ));
+    query.push_bind(user_uuid);
+
+    if let Some((created_at, id)) = cursor {
+        query.push(" AND (created_at, id) < (").push_bind(created_at);
+        query.push(", ").push_bind(id).push(")");
+    }
+    if let Some(from) = filter.from {
+        query.push(" AND created_at >= ").push_bind(from);
+    }
+    if let Some(to) = filter.to {
+        query.push(" AND created_at < ").push_bind(to);
+    }
+    if let Some(updated_since) = filter.updated_since {
+        query.push(" AND updated_at >= ").push_bind(updated_since);
+    }
+    if let Some(mood_min) = filter.mood_min {
+        query.push(" AND mood_score >= ").push_bind(mood_min);
+    }
+    if let Some(mood_max) = filter.mood_max {
+        query.push(" AND mood_score <= ").push_bind(mood_max);
+    }
+    if let Some(tag) = filter.tag {
+        // Tags of server-encrypted entries are matched through their blind index
+        match &cipher {
+            EntryCipher::Server(data_key) => {
+                let index = tag_blind_index(data_key, &tag)?;
+                query.push(" AND tag_index @> ARRAY[").push_bind(index).push("]");
+            }
+            EntryCipher::Client => {
+                query.push(" AND tags @> ARRAY[").push_bind(tag).push("]");
+            }
+        }
+    }
+
+    // Fetch one extra row to learn whether another page follows
+    query.push(" ORDER BY created_at DESC, id DESC LIMIT ").push_bind(limit + 1);
+
+    let mut entries = query
+        .build_query_as::<JournalEntry>()
+        .fetch_all(&state.db)
+        .await?;
+
+    let next_cursor = if entries.len() as i64 > limit {
+        entries.truncate(limit as usize);
+        entries.last().map(encode_cursor)
+    } else {
+        None
+    };
 
     let mut response_entries = Vec::new();
     for entry in entries {
-        let decrypted_content = decrypt_text(&entry.content)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        response_entries.push(JournalEntryResponse {
-            id: entry.id,
-            title: entry.title,
-            content: decrypted_content,
-            mood_score: entry.mood_score,
-            tags: entry.tags,
-            created_at: entry.created_at,
-            updated_at: entry.updated_at,
-        });
+        response_entries.push(entry_response(&cipher, entry)?);
     }
 
-    Ok(Json(response_entries))
+    Ok(Json(JournalEntryPage {
+        entries: response_entries,
+        next_cursor,
+    }))
 }
 
+/// Fetch an entry. Answers `304 Not Modified` when `If-None-Match` already
+/// names its current version.
 pub async fn get_entry(
     State(state): State<AppState>,
     Extension(user_id): Extension<String>,
     Path(entry_id): Path<Uuid>,
-) -> Result<Json<JournalEntryResponse>, StatusCode> {
-    let user_uuid = Uuid::parse_str(&user_id)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
 
-    let entry = sqlx::query_as::<_, JournalEntry>(
-        "SELECT id, user_id, title, content, mood_score, tags, created_at, updated_at FROM journal_entries WHERE id = $1 AND user_id = $2"
-    )
+    let entry = sqlx::query_as::<_, JournalEntry>(&format!(
+        "SELECT {ENTRY_COLUMNS} FROM journal_entries WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL"
+    ))
     .bind(entry_id)
     .bind(user_uuid)
     .fetch_optional(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .ok_or(StatusCode::NOT_FOUND)?;
+    .await?
+    .ok_or(AppError::NotFound("Entry"))?;
 
-    // Decrypt content for response
-    let decrypted_content = decrypt_text(&entry.content)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let etag = entry_etag(entry.version);
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        if etag_matches(if_none_match, &etag, true) {
+            return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+        }
+    }
 
-    Ok(Json(JournalEntryResponse {
-        id: entry.id,
-        title: entry.title,
-        content: decrypted_content,
-        mood_score: entry.mood_score,
-        tags: entry.tags,
-        created_at: entry.created_at,
-        updated_at: entry.updated_at,
-    }))
+    Ok(entry_with_etag(StatusCode::OK, entry_response(&cipher, entry)?))
 }
 
+/// Update an entry.
+///
+/// When the request carries `If-Match` or a `version` field that no longer
+/// names the current version, nothing is written and the current entry is
+/// returned with `412 Precondition Failed`.
 pub async fn update_entry(
     State(state): State<AppState>,
     Extension(user_id): Extension<String>,
     Path(entry_id): Path<Uuid>,
-    Json(payload): Json<UpdateJournalEntry>,
-) -> Result<Json<JournalEntryResponse>, StatusCode> {
-    let user_uuid = Uuid::parse_str(&user_id)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    headers: HeaderMap,
+    ValidatedJson(payload): ValidatedJson<UpdateJournalEntry>,
+) -> Result<Response, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let mut tx = state.db.begin().await?;
 
     // First check if entry exists and belongs to user
-    let existing_entry = sqlx::query_as::<_, JournalEntry>(
-        "SELECT id, user_id, title, content, mood_score, tags, created_at, updated_at FROM journal_entries WHERE id = $1 AND user_id = $2"
-    )
-    .bind(entry_id)
-    .bind(user_uuid)
-    .fetch_optional(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .ok_or(StatusCode::NOT_FOUND)?;
-
-    // Prepare update fields
-    let title = payload.title.as_ref().unwrap_or(&existing_entry.title);
-    
-    let encrypted_content = if let Some(content) = &payload.content {
-        encrypt_text(content)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    } else {
-        existing_entry.content.clone()
-    };
+    let existing_entry = lock_entry(&mut tx, user_uuid, entry_id).await?;
 
-    let mood_score = payload.mood_score.or(existing_entry.mood_score);
-    let tags = payload.tags.as_ref().or(existing_entry.tags.as_ref());
-    let now = OffsetDateTime::now_utc();
+    if is_stale(&headers, payload.version, existing_entry.version) {
+        let current = entry_response(&cipher, existing_entry)?;
+        return Ok(entry_with_etag(StatusCode::PRECONDITION_FAILED, current));
+    }
 
-    let updated_entry = sqlx::query_as::<_, JournalEntry>(
-        r#"
-        UPDATE journal_entries 
-        SET title = $1, content = $2, mood_score = $3, tags = $4, updated_at = $5
-        WHERE id = $6 AND user_id = $7
-        RETURNING id, user_id, title, content, mood_score, tags, created_at, updated_at
-        "#
-    )
-    .bind(title)
-    .bind(&encrypted_content)
-    .bind(mood_score)
-    .bind(tags)
-    .bind(now)
-    .bind(entry_id)
-    .bind(user_uuid)
-    .fetch_one(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    // Decrypt content for response
-    let decrypted_content = decrypt_text(&updated_entry.content)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    Ok(Json(JournalEntryResponse {
-        id: updated_entry.id,
-        title: updated_entry.title,
-        content: decrypted_content,
-        mood_score: updated_entry.mood_score,
-        tags: updated_entry.tags,
-        created_at: updated_entry.created_at,
-        updated_at: updated_entry.updated_at,
-    }))
+    let updated_entry =
+        apply_update(&mut tx, &cipher, &state.config.limits, existing_entry, &payload).await?;
+
+    tx.commit().await?;
+
+    Ok(entry_with_etag(StatusCode::OK, entry_response(&cipher, updated_entry)?))
 }
 
-pub async fn delete_entry(
+/// Apply a JSON Merge Patch (RFC 7396) to an entry.
+///
+/// Unlike `PUT`, a member set to `null` is removed, so `mood_score`, `tags`
+/// and `kdf` can be cleared. `If-Match` is honored the same way as for `PUT`.
+pub async fn patch_entry(
     State(state): State<AppState>,
     Extension(user_id): Extension<String>,
     Path(entry_id): Path<Uuid>,
-) -> Result<Json<Value>, StatusCode> {
-    let user_uuid = Uuid::parse_str(&user_id)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    headers: HeaderMap,
+    Json(patch): Json<Value>,
+) -> Result<Response, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
 
-    let result = sqlx::query(
-        "DELETE FROM journal_entries WHERE id = $1 AND user_id = $2"
-    )
-    .bind(entry_id)
-    .bind(user_uuid)
-    .execute(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Anything but an object would replace the whole entry
+    if !patch.is_object() {
+        return Err(AppError::BadRequest("Merge patch must be a JSON object".to_string()));
+    }
 
-    if result.rows_affected() == 0 {
-        return Err(StatusCode::NOT_FOUND);
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    let existing_entry = lock_entry(&mut tx, user_uuid, entry_id).await?;
+    let current = entry_response(&cipher, existing_entry)?;
+
+    if is_stale(&headers, None, current.version) {
+        return Ok(entry_with_etag(StatusCode::PRECONDITION_FAILED, current));
     }
 
+    let document = apply_patch(
+        EntryDocument {
+            title: current.title.clone(),
+            content: current.content.clone(),
+            ciphertext: current.ciphertext.clone(),
+            kdf: current.kdf.clone(),
+            mood_score: current.mood_score,
+            tags: current.tags.clone(),
+        },
+        &patch,
+    )?;
+
+    let updated_entry =
+        replace_entry(&mut tx, &cipher, &state.config.limits, user_uuid, &current, &document).await?;
+
+    tx.commit().await?;
+
+    Ok(entry_with_etag(StatusCode::OK, entry_response(&cipher, updated_entry)?))
+}
+
+pub async fn delete_entry(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path(entry_id): Path<Uuid>,
+) -> Result<Json<Value>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+
+    let mut tx = state.db.begin().await?;
+
+    // Move the entry to the trash; the trash purger deletes it for good later
+    let entry = lock_entry(&mut tx, user_uuid, entry_id).await?;
+    trash_entry(&mut tx, &entry).await?;
+
+    tx.commit().await?;
+
     Ok(Json(json!({
-        "message": "Entry deleted successfully"
+        "message": "Entry moved to trash"
     })))
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> EntryDocument {
+        EntryDocument {
+            title: "Monday".to_string(),
+            content: Some("Rain all day".to_string()),
+            ciphertext: None,
+            kdf: None,
+            mood_score: Some(4),
+            tags: Some(vec!["weather".to_string()]),
+        }
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        let mut target = json!({"a": "b", "c": {"d": "e", "f": "g"}});
+        merge_patch(&mut target, &json!({"a": "z", "c": {"f": null}}));
+        assert_eq!(target, json!({"a": "z", "c": {"d": "e"}}));
+
+        // Non-object patches and targets are replaced wholesale
+        let mut target = json!({"a": ["b"]});
+        merge_patch(&mut target, &json!({"a": {"c": 1}}));
+        assert_eq!(target, json!({"a": {"c": 1}}));
+
+        let mut target = json!({"a": 1});
+        merge_patch(&mut target, &json!(["x"]));
+        assert_eq!(target, json!(["x"]));
+
+        let mut target = json!({"a": 1});
+        merge_patch(&mut target, &json!({}));
+        assert_eq!(target, json!({"a": 1}));
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let entry = JournalEntry {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            title: String::new(),
+            content: String::new(),
+            mood_score: None,
+            tags: None,
+            client_kdf: None,
+            metadata_encrypted: true,
+            tags_position_bound: true,
+            created_at: OffsetDateTime::from_unix_timestamp_nanos(1_700_000_000_123_456_789).unwrap(),
+            updated_at: OffsetDateTime::now_utc(),
+            deleted_at: None,
+            version: 1,
+        };
+
+        assert_eq!(decode_cursor(&encode_cursor(&entry)), Some((entry.created_at, entry.id)));
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        assert_eq!(decode_cursor("not hex"), None);
+        assert_eq!(decode_cursor(&hex::encode("no separator")), None);
+        assert_eq!(decode_cursor(&hex::encode("12:not-a-uuid")), None);
+        assert_eq!(decode_cursor(&hex::encode(format!("soon:{}", Uuid::nil()))), None);
+    }
+
+    #[test]
+    fn patch_updates_and_clears_fields() {
+        let patched = apply_patch(document(), &json!({"mood_score": 7, "tags": null})).unwrap();
+        assert_eq!(patched.title, "Monday");
+        assert_eq!(patched.mood_score, Some(7));
+        assert_eq!(patched.tags, None);
+    }
+
+    #[test]
+    fn patch_removing_title_fails_validation() {
+        let err = apply_patch(document(), &json!({"title": null})).unwrap_err();
+        let AppError::Validation(errors) = err else {
+            panic!("expected a validation error, got {err:?}");
+        };
+        assert!(serde_json::to_value(errors).unwrap().get("title").is_some());
+    }
+}
@@ -0,0 +1,107 @@
+use axum::{
+    extract::{Extension, State},
+    response::Json,
+};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::db::models::{ClientKeyMaterial, ClientKeyMaterialResponse, UpdateClientKeyMaterial};
+use crate::error::AppError;
+use crate::validation::ValidatedJson;
+use crate::AppState;
+
+pub async fn get_key_material(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+) -> Result<Json<ClientKeyMaterialResponse>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+
+    let material = sqlx::query_as::<_, ClientKeyMaterial>(
+        "SELECT wrapped_key, kdf, updated_at FROM client_key_material WHERE user_id = $1"
+    )
+    .bind(user_uuid)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(AppError::NotFound("Key material"))?;
+
+    Ok(Json(ClientKeyMaterialResponse {
+        wrapped_key: material.wrapped_key,
+        kdf: material.kdf.0,
+        updated_at: material.updated_at,
+    }))
+}
+
+/// Store the user's client-wrapped key material.
+///
+/// The first call switches the account to client-side encryption. That is
+/// only possible while the user has no server-encrypted entries, and the
+/// now unused server data key is destroyed.
+pub async fn put_key_material(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    ValidatedJson(payload): ValidatedJson<UpdateClientKeyMaterial>,
+) -> Result<Json<ClientKeyMaterialResponse>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+
+    let mut tx = state.db.begin().await?;
+
+    let client_encrypted = sqlx::query_scalar::<_, bool>(
+        "SELECT client_encrypted FROM users WHERE id = $1 FOR UPDATE"
+    )
+    .bind(user_uuid)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(AppError::NotFound("User"))?;
+
+    if !client_encrypted {
+        let entry_count = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM journal_entries WHERE user_id = $1"
+        )
+        .bind(user_uuid)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if entry_count > 0 {
+            return Err(AppError::Conflict(
+                "Client-side encryption can only be enabled before any entries exist".to_string(),
+            ));
+        }
+
+        sqlx::query("UPDATE users SET client_encrypted = TRUE, updated_at = $1 WHERE id = $2")
+            .bind(OffsetDateTime::now_utc())
+            .bind(user_uuid)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("DELETE FROM user_keys WHERE user_id = $1")
+            .bind(user_uuid)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let now = OffsetDateTime::now_utc();
+
+    let material = sqlx::query_as::<_, ClientKeyMaterial>(
+        r#"
+        INSERT INTO client_key_material (user_id, wrapped_key, kdf, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (user_id) DO UPDATE SET wrapped_key = $2, kdf = $3, updated_at = $5
+        RETURNING wrapped_key, kdf, updated_at
+        "#
+    )
+    .bind(user_uuid)
+    .bind(&payload.wrapped_key)
+    .bind(sqlx::types::Json(&payload.kdf))
+    .bind(now)
+    .bind(now)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(ClientKeyMaterialResponse {
+        wrapped_key: material.wrapped_key,
+        kdf: material.kdf.0,
+        updated_at: material.updated_at,
+    }))
+}
@@ -0,0 +1,39 @@
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use std::fmt::Write;
+
+use crate::metrics::{render, write_header};
+use crate::AppState;
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Prometheus scrape endpoint, served only on the `METRICS_ADDR` admin
+/// listener.
+pub async fn metrics(State(state): State<AppState>) -> Response {
+    let mut out = String::new();
+
+    // Pool gauges are read at scrape time rather than tracked
+    let size = state.db.size();
+    let idle = u32::try_from(state.db.num_idle()).unwrap_or(size);
+    write_header(&mut out, "db_pool_connections", "gauge", "Open database connections.");
+    let _ = writeln!(out, "db_pool_connections{{state=\"idle\"}} {idle}");
+    let _ = writeln!(out, "db_pool_connections{{state=\"in_use\"}} {}", size.saturating_sub(idle));
+    write_header(
+        &mut out,
+        "db_pool_max_connections",
+        "gauge",
+        "Largest number of connections the pool will open.",
+    );
+    let _ = writeln!(out, "db_pool_max_connections {}", state.config.database.max_connections);
+
+    render(&mut out);
+
+    let mut response = out.into_response();
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_TEXT));
+    response
+}
@@ -1,2 +1,9 @@
 pub mod auth;
-pub mod journal; 
\ No newline at end of file
+pub mod health;
+pub mod journal;
+pub mod keys;
+pub mod metrics;
+pub mod revisions;
+pub mod search;
+pub mod sync;
+pub mod trash;
@@ -0,0 +1,222 @@
+use axum::{
+    extract::{Extension, Path, State},
+    response::Json,
+};
+use sqlx::PgConnection;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::db::models::{
+    JournalEntry, JournalEntryResponse, JournalEntryRevision, JournalEntryRevisionResponse,
+    RevisionSummary,
+};
+use crate::error::AppError;
+use crate::quota::{self, STORED_BYTES};
+use crate::routes::journal::{entry_response, store_content, store_metadata, ENTRY_COLUMNS};
+use crate::routes::sync::next_change_seq;
+use crate::utils::keys::{entry_cipher, EntryCipher};
+use crate::utils::search::index_entry;
+use crate::AppState;
+
+/// Revisions kept per entry unless `REVISION_RETENTION` says otherwise.
+pub const DEFAULT_REVISION_RETENTION: i64 = 20;
+
+/// Revision columns in the shape of a `JournalEntryRevision`.
+const REVISION_COLUMNS: &str = "r.revision, r.entry_id AS id, r.user_id, r.title, r.content, r.mood_score, r.tags, r.client_kdf, r.metadata_encrypted, r.tags_position_bound, e.created_at, r.updated_at, NULL::timestamptz AS deleted_at, r.version, r.replaced_at";
+
+/// Copy the current version of an entry into its revision history, then drop
+/// the oldest revisions beyond `retention`, keeping the owner's storage total
+/// in step. The entry row must already be locked by the caller's transaction.
+pub async fn record_revision(
+    conn: &mut PgConnection,
+    entry_id: Uuid,
+    retention: i64,
+) -> Result<(), sqlx::Error> {
+    let (user_id, recorded) = sqlx::query_as::<_, (Uuid, i64)>(&format!(
+        r#"
+        INSERT INTO journal_entry_revisions
+            (entry_id, revision, user_id, title, content, mood_score, tags, client_kdf, metadata_encrypted, tags_position_bound, updated_at, version, replaced_at)
+        SELECT id,
+               COALESCE((SELECT MAX(revision) FROM journal_entry_revisions WHERE entry_id = $1), 0) + 1,
+               user_id, title, content, mood_score, tags, client_kdf, metadata_encrypted, tags_position_bound, updated_at, version, $2
+        FROM journal_entries WHERE id = $1
+        RETURNING user_id, {STORED_BYTES}
+        "#
+    ))
+    .bind(entry_id)
+    .bind(OffsetDateTime::now_utc())
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let pruned = sqlx::query_scalar::<_, i64>(&format!(
+        r#"
+        WITH pruned AS (
+            DELETE FROM journal_entry_revisions
+            WHERE entry_id = $1
+              AND revision <= (SELECT MAX(revision) FROM journal_entry_revisions WHERE entry_id = $1) - $2
+            RETURNING {STORED_BYTES} AS bytes
+        )
+        SELECT COALESCE(SUM(bytes), 0)::BIGINT FROM pruned
+        "#
+    ))
+    .bind(entry_id)
+    .bind(retention)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    quota::add_usage(conn, user_id, recorded - pruned).await
+}
+
+async fn entry_exists(
+    conn: &mut PgConnection,
+    entry_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, AppError> {
+    sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM journal_entries WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL)"
+    )
+    .bind(entry_id)
+    .bind(user_id)
+    .fetch_one(conn)
+    .await
+    .map_err(AppError::from)
+}
+
+async fn fetch_revision(
+    conn: &mut PgConnection,
+    entry_id: Uuid,
+    user_id: Uuid,
+    revision: i32,
+) -> Result<JournalEntryRevision, AppError> {
+    sqlx::query_as::<_, JournalEntryRevision>(&format!(
+        r#"
+        SELECT {REVISION_COLUMNS} FROM journal_entry_revisions r
+        JOIN journal_entries e ON e.id = r.entry_id
+        WHERE r.entry_id = $1 AND r.user_id = $2 AND r.revision = $3 AND e.deleted_at IS NULL
+        "#
+    ))
+    .bind(entry_id)
+    .bind(user_id)
+    .bind(revision)
+    .fetch_optional(conn)
+    .await?
+    .ok_or(AppError::NotFound("Revision"))
+}
+
+/// List an entry's earlier versions, newest first.
+pub async fn get_revisions(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path(entry_id): Path<Uuid>,
+) -> Result<Json<Vec<RevisionSummary>>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+
+    let mut conn = state.db.acquire().await?;
+
+    if !entry_exists(&mut conn, entry_id, user_uuid).await? {
+        return Err(AppError::NotFound("Entry"));
+    }
+
+    let revisions = sqlx::query_as::<_, RevisionSummary>(
+        r#"
+        SELECT revision, updated_at, replaced_at FROM journal_entry_revisions
+        WHERE entry_id = $1 AND user_id = $2
+        ORDER BY revision DESC
+        "#
+    )
+    .bind(entry_id)
+    .bind(user_uuid)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(Json(revisions))
+}
+
+pub async fn get_revision(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path((entry_id, revision)): Path<(Uuid, i32)>,
+) -> Result<Json<JournalEntryRevisionResponse>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let mut conn = state.db.acquire().await?;
+    let revision = fetch_revision(&mut conn, entry_id, user_uuid, revision).await?;
+
+    Ok(Json(JournalEntryRevisionResponse {
+        revision: revision.revision,
+        replaced_at: revision.replaced_at,
+        entry: entry_response(&cipher, revision.entry)?,
+    }))
+}
+
+/// Make an earlier version the current one. The version being replaced is
+/// recorded as a new revision, so a restore can itself be undone.
+pub async fn restore_revision(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path((entry_id, revision)): Path<(Uuid, i32)>,
+) -> Result<Json<JournalEntryResponse>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    sqlx::query("SELECT id FROM journal_entries WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL FOR UPDATE")
+        .bind(entry_id)
+        .bind(user_uuid)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(AppError::NotFound("Entry"))?;
+
+    let revision = fetch_revision(&mut tx, entry_id, user_uuid, revision).await?;
+    let mood_score = revision.entry.mood_score;
+
+    // Re-seal the old version rather than copying it, so it is stored in the current format
+    let restored = entry_response(&cipher, revision.entry)?;
+    let stored_content = store_content(
+        &cipher,
+        user_uuid,
+        entry_id,
+        restored.content.as_deref(),
+        restored.ciphertext.as_deref(),
+    )?;
+    let metadata = store_metadata(&cipher, user_uuid, entry_id, &restored.title, restored.tags.as_deref())?;
+
+    record_revision(&mut tx, entry_id, state.config.limits.revision_retention).await?;
+
+    let change_seq = next_change_seq(&mut tx, user_uuid).await?;
+    let stored_bytes = quota::stored_bytes(&metadata.title, &stored_content, metadata.tags.as_deref());
+    quota::charge(&mut tx, &state.config.limits, user_uuid, entry_id, stored_bytes).await?;
+
+    let entry = sqlx::query_as::<_, JournalEntry>(&format!(
+        r#"
+        UPDATE journal_entries
+        SET title = $1, content = $2, mood_score = $3, tags = $4, tag_index = $5,
+            metadata_encrypted = $6, tags_position_bound = $6, client_kdf = $7, updated_at = $8, version = version + 1,
+            change_seq = $9
+        WHERE id = $10
+        RETURNING {ENTRY_COLUMNS}
+        "#
+    ))
+    .bind(&metadata.title)
+    .bind(&stored_content)
+    .bind(mood_score)
+    .bind(&metadata.tags)
+    .bind(&metadata.tag_index)
+    .bind(metadata.encrypted)
+    .bind(restored.kdf.map(sqlx::types::Json))
+    .bind(OffsetDateTime::now_utc())
+    .bind(change_seq)
+    .bind(entry_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if let (EntryCipher::Server(data_key), Some(content)) = (&cipher, restored.content.as_deref()) {
+        index_entry(&mut tx, data_key, user_uuid, entry_id, &restored.title, content).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Json(entry_response(&cipher, entry)?))
+}
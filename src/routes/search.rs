@@ -0,0 +1,165 @@
+use axum::{
+    extract::{Extension, Query, State},
+    response::Json,
+};
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::db::models::{JournalEntry, SearchHit, SearchQuery, SearchResults};
+use crate::error::AppError;
+use crate::routes::journal::{entry_response, ENTRY_COLUMNS};
+use crate::utils::encryption::DataKey;
+use crate::utils::keys::{entry_cipher, EntryCipher};
+use crate::utils::search::{
+    clause_tokens, content_offset, match_sequence, open_positions, parse_query,
+    snippet, Postings,
+};
+use crate::AppState;
+
+const DEFAULT_RESULTS: i64 = 20;
+const MAX_RESULTS: i64 = 100;
+
+/// Search the user's entries.
+///
+/// Every clause of the query must match. Results are ranked by TF-IDF over
+/// the encrypted index; entries and snippets are decrypted only for the
+/// returned page. Client-encrypted entries cannot be searched by the server,
+/// and entries written before search existed only once the background
+/// indexer has reached them.
+pub async fn search_entries(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResults>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_RESULTS);
+    if !(1..=MAX_RESULTS).contains(&limit) {
+        return Err(AppError::BadRequest(format!("`limit` must be between 1 and {MAX_RESULTS}")));
+    }
+
+    let clauses = parse_query(&query.q);
+    if clauses.is_empty() {
+        return Err(AppError::BadRequest("Search query has no terms".to_string()));
+    }
+
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+    let EntryCipher::Server(data_key) = &cipher else {
+        return Err(AppError::BadRequest(
+            "Client-encrypted entries cannot be searched on the server".to_string(),
+        ));
+    };
+
+    let clause_tokens = clauses
+        .iter()
+        .map(|clause| clause_tokens(data_key, clause))
+        .collect::<Result<Vec<_>, _>>()?;
+    let tokens: Vec<String> = clause_tokens
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    let postings = load_postings(&state.db, data_key, user_uuid, &tokens).await?;
+
+    let entry_count = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM journal_entries WHERE user_id = $1 AND deleted_at IS NULL"
+    )
+    .bind(user_uuid)
+    .fetch_one(&state.db)
+    .await?;
+
+    let matches: Vec<Postings> = clause_tokens
+        .iter()
+        .map(|tokens| match_sequence(tokens, &postings))
+        .collect();
+
+    // Entries matching every clause, scored by summed TF-IDF of the clauses
+    let mut hits: Vec<(Uuid, f64, Vec<u32>)> = Vec::new();
+    if let Some((first, rest)) = matches.split_first() {
+        for entry_id in first.keys() {
+            if !rest.iter().all(|clause| clause.contains_key(entry_id)) {
+                continue;
+            }
+
+            let mut score = 0.0;
+            let mut positions = Vec::new();
+            for clause in &matches {
+                let clause_positions = &clause[entry_id];
+                let tf = clause_positions.len() as f64;
+                let idf = (1.0 + entry_count as f64 / clause.len() as f64).ln();
+                score += (1.0 + tf.ln()) * idf;
+                positions.extend_from_slice(clause_positions);
+            }
+            positions.sort_unstable();
+            hits.push((*entry_id, score, positions));
+        }
+    }
+
+    hits.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    hits.truncate(limit as usize);
+
+    let entry_ids: Vec<Uuid> = hits.iter().map(|(entry_id, _, _)| *entry_id).collect();
+    let mut entries: HashMap<Uuid, JournalEntry> = sqlx::query_as::<_, JournalEntry>(&format!(
+        "SELECT {ENTRY_COLUMNS} FROM journal_entries WHERE user_id = $1 AND id = ANY($2) AND deleted_at IS NULL"
+    ))
+    .bind(user_uuid)
+    .bind(&entry_ids)
+    .fetch_all(&state.db)
+    .await?
+    .into_iter()
+    .map(|entry| (entry.id, entry))
+    .collect();
+
+    let mut results = Vec::new();
+    for (entry_id, score, positions) in hits {
+        let Some(entry) = entries.remove(&entry_id) else {
+            continue;
+        };
+        let entry = entry_response(&cipher, entry)?;
+
+        // Show the first match in the content, or its opening if only the title matched
+        let offset = content_offset(&entry.title);
+        let first_word = positions
+            .iter()
+            .find(|position| **position >= offset)
+            .map_or(0, |position| (position - offset) as usize);
+        let snippet = snippet(entry.content.as_deref().unwrap_or_default(), first_word);
+
+        results.push(SearchHit { entry, score, snippet });
+    }
+
+    Ok(Json(SearchResults { results }))
+}
+
+/// Fetch and decrypt the index rows for `tokens`, keyed by token hash.
+/// Entries in the trash are left out.
+async fn load_postings(
+    db: &PgPool,
+    data_key: &DataKey,
+    user_id: Uuid,
+    tokens: &[String],
+) -> Result<HashMap<String, Postings>, AppError> {
+    let rows = sqlx::query_as::<_, (Uuid, String, String)>(
+        r#"
+        SELECT s.entry_id, s.token_hash, s.positions FROM search_index s
+        JOIN journal_entries e ON e.id = s.entry_id
+        WHERE s.user_id = $1 AND s.token_hash = ANY($2) AND e.deleted_at IS NULL
+        "#
+    )
+    .bind(user_id)
+    .bind(tokens)
+    .fetch_all(db)
+    .await?;
+
+    let mut postings: HashMap<String, Postings> = HashMap::new();
+    for (entry_id, token_hash, positions) in rows {
+        let positions = open_positions(data_key, user_id, entry_id, &positions)?;
+        postings.entry(token_hash).or_default().insert(entry_id, positions);
+    }
+
+    Ok(postings)
+}
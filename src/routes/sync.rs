@@ -0,0 +1,275 @@
+use axum::{
+    extract::{Extension, Query, State},
+    response::Json,
+};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::config::LimitsConfig;
+use crate::db::models::{
+    JournalEntry, SyncChange, SyncFeed, SyncMutation, SyncMutationResult, SyncOperation, SyncPush,
+    SyncPushResponse, SyncQuery, SyncedEntry,
+};
+use crate::error::AppError;
+use crate::routes::journal::{
+    apply_update, entry_response, insert_entry, lock_entry, trash_entry, ENTRY_COLUMNS,
+};
+use crate::utils::keys::{entry_cipher, EntryCipher};
+use crate::validation::Validate;
+use crate::AppState;
+
+const DEFAULT_CHANGES: i64 = 100;
+const MAX_CHANGES: i64 = 500;
+const MAX_MUTATIONS: usize = 100;
+
+const STATUS_APPLIED: &str = "applied";
+const STATUS_CONFLICT: &str = "conflict";
+
+/// Hand out the user's next change sequence number.
+///
+/// This locks the user row until the transaction ends, so numbers become
+/// visible in order. Lock the entry rows being changed first, so locks are
+/// always taken entries before user.
+pub async fn next_change_seq(conn: &mut PgConnection, user_id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>(
+        "UPDATE users SET change_seq = change_seq + 1 WHERE id = $1 RETURNING change_seq"
+    )
+    .bind(user_id)
+    .fetch_one(conn)
+    .await
+}
+
+/// List changes to the user's entries after the `since` token, oldest first.
+///
+/// Each entry appears once, at its latest change. Trashed and permanently
+/// deleted entries are reported as deleted, without content.
+pub async fn get_changes(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Query(query): Query<SyncQuery>,
+) -> Result<Json<SyncFeed>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_CHANGES);
+    if !(1..=MAX_CHANGES).contains(&limit) {
+        return Err(AppError::BadRequest(format!("`limit` must be between 1 and {MAX_CHANGES}")));
+    }
+    let since = match query.since.as_deref() {
+        Some(token) => token
+            .parse::<i64>()
+            .ok()
+            .filter(|seq| *seq >= 0)
+            .ok_or_else(|| AppError::BadRequest("Invalid sync token".to_string()))?,
+        None => 0,
+    };
+
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let entries = sqlx::query_as::<_, SyncedEntry>(&format!(
+        "SELECT change_seq, {ENTRY_COLUMNS} FROM journal_entries WHERE user_id = $1 AND change_seq > $2 ORDER BY change_seq LIMIT $3"
+    ))
+    .bind(user_uuid)
+    .bind(since)
+    .bind(limit + 1)
+    .fetch_all(&state.db)
+    .await?;
+
+    let tombstones = sqlx::query_as::<_, (Uuid, i64)>(
+        "SELECT entry_id, change_seq FROM entry_tombstones WHERE user_id = $1 AND change_seq > $2 ORDER BY change_seq LIMIT $3"
+    )
+    .bind(user_uuid)
+    .bind(since)
+    .bind(limit + 1)
+    .fetch_all(&state.db)
+    .await?;
+
+    // Merge both by sequence number and keep one page, before decrypting anything
+    let mut pending: Vec<(i64, Uuid, Option<JournalEntry>)> = entries
+        .into_iter()
+        .map(|synced| (synced.change_seq, synced.entry.id, Some(synced.entry)))
+        .chain(tombstones.into_iter().map(|(entry_id, seq)| (seq, entry_id, None)))
+        .collect();
+    pending.sort_by_key(|(seq, _, _)| *seq);
+
+    let has_more = pending.len() as i64 > limit;
+    pending.truncate(limit as usize);
+    let next_token = pending.last().map_or(since, |(seq, _, _)| *seq).to_string();
+
+    let mut changes = Vec::new();
+    for (seq, entry_id, entry) in pending {
+        let entry = match entry {
+            Some(entry) if entry.deleted_at.is_none() => Some(entry_response(&cipher, entry)?),
+            _ => None,
+        };
+        changes.push(SyncChange {
+            entry_id,
+            seq,
+            deleted: entry.is_none(),
+            entry,
+        });
+    }
+
+    Ok(Json(SyncFeed {
+        changes,
+        next_token,
+        has_more,
+    }))
+}
+
+/// Apply a batch of offline mutations in order.
+///
+/// Each mutation is applied in its own transaction and reported as applied
+/// or as a conflict; a conflict does not stop the rest of the batch. A
+/// mutation pushed again is not re-applied and reports its first outcome.
+pub async fn push_changes(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Json(push): Json<SyncPush>,
+) -> Result<Json<SyncPushResponse>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+
+    if push.mutations.len() > MAX_MUTATIONS {
+        return Err(AppError::BadRequest(format!("At most {MAX_MUTATIONS} mutations can be pushed at once")));
+    }
+
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let mut results = Vec::new();
+    for mutation in &push.mutations {
+        results.push(apply_mutation(&state, &cipher, user_uuid, mutation).await?);
+    }
+
+    Ok(Json(SyncPushResponse { results }))
+}
+
+async fn apply_mutation(
+    state: &AppState,
+    cipher: &EntryCipher,
+    user_id: Uuid,
+    mutation: &SyncMutation,
+) -> Result<SyncMutationResult, AppError> {
+    let mut tx = state.db.begin().await?;
+
+    // Claim the mutation ID first, so a concurrent retry waits for this attempt
+    let claimed = sqlx::query(
+        r#"
+        INSERT INTO sync_mutations (user_id, mutation_id, entry_id, status)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id, mutation_id) DO NOTHING
+        "#
+    )
+    .bind(user_id)
+    .bind(mutation.mutation_id)
+    .bind(mutation.entry_id)
+    .bind(STATUS_APPLIED)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        == 1;
+
+    if !claimed {
+        return sqlx::query_as::<_, SyncMutationResult>(
+            "SELECT mutation_id, entry_id, status, reason, version FROM sync_mutations WHERE user_id = $1 AND mutation_id = $2"
+        )
+        .bind(user_id)
+        .bind(mutation.mutation_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(AppError::from);
+    }
+
+    let outcome = apply_operation(&mut tx, &state.config.limits, cipher, user_id, mutation).await;
+
+    let (status, reason, version) = match outcome {
+        Ok(version) => {
+            sqlx::query("UPDATE sync_mutations SET version = $1 WHERE user_id = $2 AND mutation_id = $3")
+                .bind(version)
+                .bind(user_id)
+                .bind(mutation.mutation_id)
+                .execute(&mut *tx)
+                .await?;
+
+            tx.commit().await?;
+
+            (STATUS_APPLIED, None, Some(version))
+        }
+        Err(err) if err.status().is_server_error() => return Err(err),
+        Err(err) => {
+            tx.rollback().await?;
+
+            let reason = match err {
+                AppError::NotFound(_) => "not_found",
+                AppError::Conflict(_) => "already_exists",
+                AppError::PreconditionFailed => "version_mismatch",
+                AppError::QuotaExceeded(_) => "quota_exceeded",
+                _ => "invalid",
+            };
+
+            let current_version = sqlx::query_scalar::<_, i64>(
+                "SELECT version FROM journal_entries WHERE id = $1 AND user_id = $2"
+            )
+            .bind(mutation.entry_id)
+            .bind(user_id)
+            .fetch_optional(&state.db)
+            .await?;
+
+            sqlx::query(
+                r#"
+                INSERT INTO sync_mutations (user_id, mutation_id, entry_id, status, reason, version)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (user_id, mutation_id) DO NOTHING
+                "#
+            )
+            .bind(user_id)
+            .bind(mutation.mutation_id)
+            .bind(mutation.entry_id)
+            .bind(STATUS_CONFLICT)
+            .bind(reason)
+            .bind(current_version)
+            .execute(&state.db)
+            .await?;
+
+            (STATUS_CONFLICT, Some(reason), current_version)
+        }
+    };
+
+    Ok(SyncMutationResult {
+        mutation_id: mutation.mutation_id,
+        entry_id: mutation.entry_id,
+        status: status.to_string(),
+        reason: reason.map(str::to_string),
+        version,
+    })
+}
+
+/// Apply a single mutation, returning the entry's new version.
+async fn apply_operation(
+    conn: &mut PgConnection,
+    limits: &LimitsConfig,
+    cipher: &EntryCipher,
+    user_id: Uuid,
+    mutation: &SyncMutation,
+) -> Result<i64, AppError> {
+    match &mutation.operation {
+        SyncOperation::Create { entry } => {
+            entry.validate()?;
+            Ok(insert_entry(conn, cipher, limits, user_id, mutation.entry_id, entry).await?.version)
+        }
+        SyncOperation::Update { entry } => {
+            entry.validate()?;
+            let existing = lock_entry(conn, user_id, mutation.entry_id).await?;
+            if entry.version.is_some_and(|version| version != existing.version) {
+                return Err(AppError::PreconditionFailed);
+            }
+            Ok(apply_update(conn, cipher, limits, existing, entry).await?.version)
+        }
+        SyncOperation::Delete { version } => {
+            let existing = lock_entry(conn, user_id, mutation.entry_id).await?;
+            if version.is_some_and(|version| version != existing.version) {
+                return Err(AppError::PreconditionFailed);
+            }
+            trash_entry(conn, &existing).await?;
+            Ok(existing.version + 1)
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use axum::{
+    extract::{Extension, Path, State},
+    response::Json,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::db::models::{JournalEntry, JournalEntryResponse};
+use crate::error::AppError;
+use crate::quota::{release_purged, STORED_BYTES};
+use crate::routes::journal::{entry_response, ENTRY_COLUMNS};
+use crate::routes::sync::next_change_seq;
+use crate::utils::keys::entry_cipher;
+use crate::AppState;
+
+/// List entries in the trash, most recently deleted first.
+pub async fn get_trash(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+) -> Result<Json<Vec<JournalEntryResponse>>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let entries = sqlx::query_as::<_, JournalEntry>(&format!(
+        "SELECT {ENTRY_COLUMNS} FROM journal_entries WHERE user_id = $1 AND deleted_at IS NOT NULL ORDER BY deleted_at DESC"
+    ))
+    .bind(user_uuid)
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut response_entries = Vec::new();
+    for entry in entries {
+        response_entries.push(entry_response(&cipher, entry)?);
+    }
+
+    Ok(Json(response_entries))
+}
+
+pub async fn restore_entry(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path(entry_id): Path<Uuid>,
+) -> Result<Json<JournalEntryResponse>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+    let cipher = entry_cipher(&state.db, user_uuid).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    sqlx::query("SELECT id FROM journal_entries WHERE id = $1 AND user_id = $2 AND deleted_at IS NOT NULL FOR UPDATE")
+        .bind(entry_id)
+        .bind(user_uuid)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(AppError::NotFound("Entry"))?;
+
+    let change_seq = next_change_seq(&mut tx, user_uuid).await?;
+
+    let entry = sqlx::query_as::<_, JournalEntry>(&format!(
+        r#"
+        UPDATE journal_entries SET deleted_at = NULL, version = version + 1, change_seq = $1
+        WHERE id = $2
+        RETURNING {ENTRY_COLUMNS}
+        "#
+    ))
+    .bind(change_seq)
+    .bind(entry_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(entry_response(&cipher, entry)?))
+}
+
+/// Permanently delete an entry from the trash, along with its revisions
+/// and search index rows, freeing their storage. A tombstone is left for
+/// the sync feed.
+pub async fn purge_entry(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path(entry_id): Path<Uuid>,
+) -> Result<Json<Value>, AppError> {
+    let user_uuid = Uuid::parse_str(&user_id).map_err(|_| AppError::Unauthorized)?;
+
+    let result = sqlx::query(&format!(
+        r#"
+        WITH purged AS (
+            DELETE FROM journal_entries WHERE id = $1 AND user_id = $2 AND deleted_at IS NOT NULL
+            RETURNING id, user_id, change_seq, deleted_at, {STORED_BYTES} AS bytes
+        ),
+        {release}
+        INSERT INTO entry_tombstones (entry_id, user_id, change_seq, deleted_at)
+        SELECT id, user_id, change_seq, deleted_at FROM purged
+        ON CONFLICT (entry_id) DO UPDATE
+        SET user_id = EXCLUDED.user_id, change_seq = EXCLUDED.change_seq, deleted_at = EXCLUDED.deleted_at
+        "#,
+        release = release_purged(),
+    ))
+    .bind(entry_id)
+    .bind(user_uuid)
+    .execute(&state.db)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Entry"));
+    }
+
+    Ok(Json(json!({
+        "message": "Entry deleted permanently"
+    })))
+}
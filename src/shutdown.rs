@@ -0,0 +1,101 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// Default time allowed for in-flight requests to finish after a shutdown
+/// signal. Kept below Kubernetes' 30 second termination grace period.
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 20;
+
+/// Shared flag telling the server, readiness check and background workers
+/// that the process is shutting down.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub async fn triggered(mut self) {
+        // The sender lives in `self`, so this only fails if it was never sent
+        let _ = self.receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Short tasks that requests hand off to run after responding, such as
+/// sending mail, so shutdown can wait for them like the long-running workers.
+#[derive(Clone, Default)]
+pub struct BackgroundTasks {
+    set: Arc<Mutex<JoinSet<()>>>,
+}
+
+impl BackgroundTasks {
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut set = self.set.lock().unwrap_or_else(PoisonError::into_inner);
+        // Reap finished tasks, so the set only holds running ones
+        while set.try_join_next().is_some() {}
+        set.spawn(task);
+    }
+
+    /// Wait for every task spawned so far to finish.
+    pub async fn wait(self) {
+        let mut set = std::mem::take(&mut *self.set.lock().unwrap_or_else(PoisonError::into_inner));
+        while set.join_next().await.is_some() {}
+    }
+}
+
+/// Wait for SIGINT (Ctrl+C) or, on Unix, SIGTERM, then trigger `shutdown`.
+pub async fn listen_for_signals(shutdown: Shutdown) {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %err, "Failed to listen for Ctrl+C");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+
+    tracing::info!("Shutdown signal received; draining in-flight requests");
+    shutdown.trigger();
+}
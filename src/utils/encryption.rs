@@ -1,7 +1,34 @@
 use ring::aead::{Aad, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey, AES_256_GCM, NONCE_LEN};
 use ring::error::Unspecified;
+use ring::hkdf;
+use ring::hmac;
 use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
 use thiserror::Error;
+use uuid::Uuid;
+
+use crate::metrics;
+
+/// Length of both the master key and per-user data keys (AES-256).
+pub const KEY_LEN: usize = 32;
+
+/// Prefix of the ciphertext envelope `v1:<key id>:<hex(nonce || ciphertext || tag)>`.
+/// Anything without a version prefix is a legacy bare hex blob.
+pub const ENVELOPE_PREFIX: &str = "v1:";
+
+/// Prefix of envelopes sealed with associated data binding them to a record.
+/// Same layout as `v1`, but the ciphertext only opens with the matching AAD.
+pub const BOUND_ENVELOPE_PREFIX: &str = "v2:";
+
+/// Key ID of the single master key configured through `ENCRYPTION_KEY`,
+/// and the key legacy (unversioned) master-key ciphertexts are opened with.
+pub const LEGACY_KEY_ID: &str = "default";
+
+/// Key ID recorded in envelopes sealed with a user's data key.
+pub const DATA_KEY_ID: &str = "dek";
+
+/// HKDF salt for keys derived from a user's data key.
+const DERIVED_KEY_SALT: &[u8] = b"kryptic-journal derived keys";
 
 #[derive(Error, Debug)]
 pub enum EncryptionError {
@@ -11,8 +38,20 @@ pub enum EncryptionError {
     DecryptionFailed,
     #[error("Invalid key length")]
     InvalidKeyLength,
+    #[error("Invalid key configuration: {0}")]
+    InvalidKeyConfig(String),
+    #[error("Unknown key ID: {0}")]
+    UnknownKey(String),
+    #[error("Unsupported ciphertext version")]
+    UnsupportedVersion,
+    #[error("Ciphertext is not bound to this record")]
+    AssociatedDataMismatch,
+    #[error("Unbound ciphertexts are rejected in strict mode")]
+    UnboundRejected,
     #[error("Ring error")]
     RingError,
+    #[error("Key storage error: {0}")]
+    KeyStorage(#[from] sqlx::Error),
 }
 
 impl From<Unspecified> for EncryptionError {
@@ -21,74 +60,306 @@ impl From<Unspecified> for EncryptionError {
     }
 }
 
-pub struct EncryptionService {
-    key: Vec<u8>,
+/// A parsed ciphertext: the key ID from the envelope header (`None` for
+/// legacy blobs), whether it was sealed with associated data, and the hex body.
+struct Envelope<'a> {
+    key_id: Option<&'a str>,
+    bound: bool,
+    body: &'a str,
+}
+
+impl<'a> Envelope<'a> {
+    fn parse(ciphertext: &'a str) -> Result<Self, EncryptionError> {
+        for (prefix, bound) in [(ENVELOPE_PREFIX, false), (BOUND_ENVELOPE_PREFIX, true)] {
+            if let Some(rest) = ciphertext.strip_prefix(prefix) {
+                let (key_id, body) = rest
+                    .split_once(':')
+                    .ok_or(EncryptionError::DecryptionFailed)?;
+                return Ok(Self { key_id: Some(key_id), bound, body });
+            }
+        }
+
+        // Legacy blobs are bare hex, so a leading 'v' means a newer envelope version
+        if ciphertext.starts_with('v') {
+            return Err(EncryptionError::UnsupportedVersion);
+        }
+
+        Ok(Self { key_id: None, bound: false, body: ciphertext })
+    }
+}
+
+/// Associated data binding a journal entry field's ciphertext to its row,
+/// so it cannot be moved to another entry, another user or another column.
+pub fn entry_aad(user_id: Uuid, entry_id: Uuid, field: &str) -> Vec<u8> {
+    format!("journal_entries:{}:{}:{}", user_id, entry_id, field).into_bytes()
+}
+
+/// Seal `plaintext` under `key`, returning an envelope tagged with `key_id`.
+/// With associated data the result uses the bound (`v2`) envelope.
+fn seal(
+    key: &[u8],
+    key_id: &str,
+    rng: &SystemRandom,
+    aad: Option<&[u8]>,
+    plaintext: &[u8],
+) -> Result<String, EncryptionError> {
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce_bytes)
+        .map_err(|_| EncryptionError::RingError)?;
+
+    let nonce = Nonce::assume_unique_for_key(nonce_bytes);
+    let unbound_key = UnboundKey::new(&AES_256_GCM, key)
+        .map_err(|_| EncryptionError::RingError)?;
+
+    let mut sealing_key = SealingKey::new(unbound_key, OneNonceSequence(Some(nonce)));
+
+    let mut in_out = plaintext.to_vec();
+    sealing_key.seal_in_place_append_tag(Aad::from(aad.unwrap_or_default()), &mut in_out)
+        .map_err(|_| EncryptionError::EncryptionFailed)?;
+
+    // Prepend nonce to the encrypted data
+    let mut result = nonce_bytes.to_vec();
+    result.extend_from_slice(&in_out);
+
+    let prefix = if aad.is_some() { BOUND_ENVELOPE_PREFIX } else { ENVELOPE_PREFIX };
+    Ok(format!("{}{}:{}", prefix, key_id, hex::encode(result)))
+}
+
+/// Open the hex body of an envelope produced by [`seal`].
+fn open(key: &[u8], aad: &[u8], ciphertext_hex: &str) -> Result<Vec<u8>, EncryptionError> {
+    let ciphertext = hex::decode(ciphertext_hex)
+        .map_err(|_| EncryptionError::DecryptionFailed)?;
+
+    if ciphertext.len() < NONCE_LEN {
+        return Err(EncryptionError::DecryptionFailed);
+    }
+
+    let (nonce_bytes, encrypted_data) = ciphertext.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce_bytes)
+        .map_err(|_| EncryptionError::RingError)?;
+
+    let unbound_key = UnboundKey::new(&AES_256_GCM, key)
+        .map_err(|_| EncryptionError::RingError)?;
+
+    let mut opening_key = OpeningKey::new(unbound_key, OneNonceSequence(Some(nonce)));
+
+    let mut in_out = encrypted_data.to_vec();
+    let plaintext = opening_key.open_in_place(Aad::from(aad), &mut in_out)
+        .map_err(|_| EncryptionError::RingError)?;
+
+    Ok(plaintext.to_vec())
+}
+
+/// A user's random data-encryption key (DEK).
+///
+/// Journal content is encrypted with the DEK; the DEK itself is only ever
+/// stored wrapped by the master key, so deleting it makes the user's
+/// entries unrecoverable.
+pub struct DataKey {
+    key: [u8; KEY_LEN],
     rng: SystemRandom,
+    /// Reject ciphertexts not bound to their record, see [`EncryptionService::from_keys`].
+    strict: bool,
 }
 
-impl EncryptionService {
-    pub fn new() -> Result<Self, EncryptionError> {
-        let key_str = std::env::var("ENCRYPTION_KEY")
-            .expect("ENCRYPTION_KEY must be set in environment");
-        
-        let key = hex::decode(key_str)
+impl DataKey {
+    /// A new key, as strict as the service that wraps it (see
+    /// [`EncryptionService::is_strict`]). Legacy entries of the user may still
+    /// be unbound until the re-encryption job re-seals them.
+    pub fn generate(strict: bool) -> Result<Self, EncryptionError> {
+        let rng = SystemRandom::new();
+        let mut key = [0u8; KEY_LEN];
+        rng.fill(&mut key)
+            .map_err(|_| EncryptionError::RingError)?;
+
+        Ok(Self { key, rng, strict })
+    }
+
+    fn from_bytes(bytes: &[u8], strict: bool) -> Result<Self, EncryptionError> {
+        let key: [u8; KEY_LEN] = bytes
+            .try_into()
             .map_err(|_| EncryptionError::InvalidKeyLength)?;
-        
-        if key.len() != 32 {
-            return Err(EncryptionError::InvalidKeyLength);
-        }
 
         Ok(Self {
             key,
             rng: SystemRandom::new(),
+            strict,
         })
     }
 
-    pub fn encrypt(&self, plaintext: &str) -> Result<String, EncryptionError> {
-        let mut nonce_bytes = [0u8; NONCE_LEN];
-        self.rng.fill(&mut nonce_bytes)
-            .map_err(|_| EncryptionError::RingError)?;
+    /// Whether ciphertexts sealed before associated data was introduced, or
+    /// with coarser associated data than now, are refused.
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
 
-        let nonce = Nonce::assume_unique_for_key(nonce_bytes);
-        let unbound_key = UnboundKey::new(&AES_256_GCM, &self.key)
-            .map_err(|_| EncryptionError::RingError)?;
-        
-        let mut sealing_key = SealingKey::new(unbound_key, OneNonceSequence(Some(nonce)));
-        
-        let mut in_out = plaintext.as_bytes().to_vec();
-        sealing_key.seal_in_place_append_tag(Aad::empty(), &mut in_out)
-            .map_err(|_| EncryptionError::RingError)?;
+    /// Encrypt and bind the ciphertext to `aad`.
+    pub fn encrypt(&self, aad: &[u8], plaintext: &str) -> Result<String, EncryptionError> {
+        metrics::time_crypto("encrypt", || {
+            seal(&self.key, DATA_KEY_ID, &self.rng, Some(aad), plaintext.as_bytes())
+        })
+    }
+
+    /// Decrypt a ciphertext bound to `aad`.
+    ///
+    /// Unbound (`v1` and legacy) ciphertexts written before associated data
+    /// was introduced are accepted unless the key is strict.
+    pub fn decrypt(&self, aad: &[u8], ciphertext: &str) -> Result<String, EncryptionError> {
+        metrics::time_crypto("decrypt", || {
+            let envelope = Envelope::parse(ciphertext)?;
+            match envelope.key_id {
+                None | Some(DATA_KEY_ID) => {}
+                Some(other) => return Err(EncryptionError::UnknownKey(other.to_string())),
+            }
 
-        // Prepend nonce to the encrypted data
-        let mut result = nonce_bytes.to_vec();
-        result.extend_from_slice(&in_out);
-        
-        Ok(hex::encode(result))
+            let plaintext = if envelope.bound {
+                open(&self.key, aad, envelope.body)
+                    .map_err(|_| EncryptionError::AssociatedDataMismatch)?
+            } else if self.strict {
+                return Err(EncryptionError::UnboundRejected);
+            } else {
+                open(&self.key, &[], envelope.body)?
+            };
+
+            String::from_utf8(plaintext)
+                .map_err(|_| EncryptionError::DecryptionFailed)
+        })
+    }
+
+    /// Deterministic keyed HMAC of `value`, for equality lookups on encrypted
+    /// fields. Each `purpose` gets its own HKDF-derived key, so indexes for
+    /// different fields cannot be correlated.
+    pub fn blind_index(&self, purpose: &str, value: &str) -> Result<String, EncryptionError> {
+        let info = [purpose.as_bytes()];
+        let index_key: hmac::Key = hkdf::Salt::new(hkdf::HKDF_SHA256, DERIVED_KEY_SALT)
+            .extract(&self.key)
+            .expand(&info, hmac::HMAC_SHA256)
+            .map_err(|_| EncryptionError::RingError)?
+            .into();
+
+        Ok(hex::encode(hmac::sign(&index_key, value.as_bytes())))
+    }
+}
+
+impl Drop for DataKey {
+    fn drop(&mut self) {
+        self.key.fill(0);
     }
+}
+
+/// Holds the master key-encryption keys (KEKs) used to wrap per-user data keys.
+///
+/// Several master keys can be loaded at once so that data wrapped by a
+/// retired key stays readable while the re-encryption job moves it over
+/// to the active key.
+pub struct EncryptionService {
+    keys: HashMap<String, Vec<u8>>,
+    active_key_id: String,
+    rng: SystemRandom,
+    strict: bool,
+}
 
-    pub fn decrypt(&self, ciphertext_hex: &str) -> Result<String, EncryptionError> {
-        let ciphertext = hex::decode(ciphertext_hex)
-            .map_err(|_| EncryptionError::DecryptionFailed)?;
+/// Split `id:hex` master key entries, as listed in `ENCRYPTION_KEYS`.
+pub fn parse_master_keys(entries: &[String]) -> Result<Vec<(String, String)>, EncryptionError> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .trim()
+                .split_once(':')
+                .map(|(id, hex)| (id.to_string(), hex.to_string()))
+                .ok_or_else(|| EncryptionError::InvalidKeyConfig(
+                    "entries must look like id:hex".to_string(),
+                ))
+        })
+        .collect()
+}
 
-        if ciphertext.len() < NONCE_LEN {
-            return Err(EncryptionError::DecryptionFailed);
+impl EncryptionService {
+    /// Load master keys given as `(id, hex)`. The first key is active.
+    ///
+    /// With `strict`, data keys unwrapped by the service refuse ciphertexts
+    /// that are not bound to their record. Turn it on once the re-encryption
+    /// job has finished without failures.
+    pub fn from_keys(entries: &[(String, String)], strict: bool) -> Result<Self, EncryptionError> {
+        let active_key_id = entries
+            .first()
+            .map(|(id, _)| id.clone())
+            .ok_or_else(|| EncryptionError::InvalidKeyConfig("no master keys configured".to_string()))?;
+
+        let mut keys = HashMap::new();
+        for (id, key_str) in entries {
+            if id.is_empty() || id.contains(':') {
+                return Err(EncryptionError::InvalidKeyConfig(format!("invalid key ID {id:?}")));
+            }
+
+            let key = hex::decode(key_str)
+                .map_err(|_| EncryptionError::InvalidKeyLength)?;
+
+            if key.len() != KEY_LEN {
+                return Err(EncryptionError::InvalidKeyLength);
+            }
+
+            if keys.insert(id.clone(), key).is_some() {
+                return Err(EncryptionError::InvalidKeyConfig(format!("duplicate key ID {id:?}")));
+            }
         }
 
-        let (nonce_bytes, encrypted_data) = ciphertext.split_at(NONCE_LEN);
-        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes)
-            .map_err(|_| EncryptionError::RingError)?;
+        Ok(Self {
+            keys,
+            active_key_id,
+            rng: SystemRandom::new(),
+            strict,
+        })
+    }
 
-        let unbound_key = UnboundKey::new(&AES_256_GCM, &self.key)
-            .map_err(|_| EncryptionError::RingError)?;
-        
-        let mut opening_key = OpeningKey::new(unbound_key, OneNonceSequence(Some(nonce)));
-        
-        let mut in_out = encrypted_data.to_vec();
-        let plaintext = opening_key.open_in_place(Aad::empty(), &mut in_out)
-            .map_err(|_| EncryptionError::RingError)?;
+    /// Whether data keys refuse ciphertexts not bound to their record.
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// ID of the master key new data is wrapped with.
+    pub fn active_key_id(&self) -> &str {
+        &self.active_key_id
+    }
+
+    fn key(&self, key_id: &str) -> Result<&[u8], EncryptionError> {
+        self.keys
+            .get(key_id)
+            .map(Vec::as_slice)
+            .ok_or_else(|| EncryptionError::UnknownKey(key_id.to_string()))
+    }
 
-        String::from_utf8(plaintext.to_vec())
-            .map_err(|_| EncryptionError::DecryptionFailed)
+    fn open(&self, ciphertext: &str) -> Result<Vec<u8>, EncryptionError> {
+        let envelope = Envelope::parse(ciphertext)?;
+        let key = self.key(envelope.key_id.unwrap_or(LEGACY_KEY_ID))?;
+        open(key, &[], envelope.body)
+    }
+
+    /// Decrypt content written directly under the master key, before
+    /// per-user keys existed.
+    pub fn decrypt(&self, ciphertext: &str) -> Result<String, EncryptionError> {
+        metrics::time_crypto("decrypt", || {
+            let plaintext = self.open(ciphertext)?;
+            String::from_utf8(plaintext)
+                .map_err(|_| EncryptionError::DecryptionFailed)
+        })
+    }
+
+    pub fn wrap_key(&self, data_key: &DataKey) -> Result<String, EncryptionError> {
+        metrics::time_crypto("wrap_key", || {
+            let key = self.key(&self.active_key_id)?;
+            seal(key, &self.active_key_id, &self.rng, None, &data_key.key)
+        })
+    }
+
+    pub fn unwrap_key(&self, wrapped: &str) -> Result<DataKey, EncryptionError> {
+        metrics::time_crypto("unwrap_key", || {
+            let mut bytes = self.open(wrapped)?;
+            let data_key = DataKey::from_bytes(&bytes, self.strict);
+            bytes.fill(0);
+            data_key
+        })
     }
 }
 
@@ -100,20 +371,142 @@ impl NonceSequence for OneNonceSequence {
     }
 }
 
-// Global encryption service instance
+// Global encryption service instance, set up from the config at startup
 use std::sync::OnceLock;
 static ENCRYPTION_SERVICE: OnceLock<EncryptionService> = OnceLock::new();
 
+/// Install the master keys. Must be called once, before serving requests.
+pub fn init_encryption_service(service: EncryptionService) {
+    if ENCRYPTION_SERVICE.set(service).is_err() {
+        panic!("encryption service initialized twice");
+    }
+}
+
 pub fn get_encryption_service() -> &'static EncryptionService {
-    ENCRYPTION_SERVICE.get_or_init(|| {
-        EncryptionService::new().expect("Failed to initialize encryption service")
-    })
+    ENCRYPTION_SERVICE
+        .get()
+        .expect("encryption service is initialized at startup")
 }
 
-pub fn encrypt_text(plaintext: &str) -> Result<String, EncryptionError> {
-    get_encryption_service().encrypt(plaintext)
+pub fn encrypt_text(key: &DataKey, aad: &[u8], plaintext: &str) -> Result<String, EncryptionError> {
+    key.encrypt(aad, plaintext)
 }
 
-pub fn decrypt_text(ciphertext: &str) -> Result<String, EncryptionError> {
-    get_encryption_service().decrypt(ciphertext)
-} 
\ No newline at end of file
+pub fn decrypt_text(key: &DataKey, aad: &[u8], ciphertext: &str) -> Result<String, EncryptionError> {
+    key.decrypt(aad, ciphertext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn service(strict: bool) -> EncryptionService {
+        EncryptionService::from_keys(&[(LEGACY_KEY_ID.to_string(), KEY_HEX.to_string())], strict).unwrap()
+    }
+
+    fn data_key(strict: bool) -> DataKey {
+        DataKey::from_bytes(&hex::decode(KEY_HEX).unwrap(), strict).unwrap()
+    }
+
+    /// A `v1` envelope: sealed with the data key but no associated data.
+    fn unbound(key: &DataKey, plaintext: &str) -> String {
+        seal(&key.key, DATA_KEY_ID, &key.rng, None, plaintext.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parses_envelopes() {
+        let v1 = Envelope::parse("v1:k1:abcd").unwrap();
+        assert_eq!((v1.key_id, v1.bound, v1.body), (Some("k1"), false, "abcd"));
+
+        let v2 = Envelope::parse("v2:dek:abcd").unwrap();
+        assert_eq!((v2.key_id, v2.bound, v2.body), (Some("dek"), true, "abcd"));
+
+        let legacy = Envelope::parse("abcd").unwrap();
+        assert_eq!((legacy.key_id, legacy.bound, legacy.body), (None, false, "abcd"));
+
+        assert!(matches!(Envelope::parse("v3:dek:abcd"), Err(EncryptionError::UnsupportedVersion)));
+        assert!(matches!(Envelope::parse("v1:abcd"), Err(EncryptionError::DecryptionFailed)));
+    }
+
+    #[test]
+    fn bound_ciphertexts_only_open_with_their_aad() {
+        let key = data_key(true);
+        let ciphertext = key.encrypt(b"entry-1", "secret").unwrap();
+
+        assert!(ciphertext.starts_with("v2:dek:"));
+        assert_eq!(key.decrypt(b"entry-1", &ciphertext).unwrap(), "secret");
+        assert!(matches!(key.decrypt(b"entry-2", &ciphertext), Err(EncryptionError::AssociatedDataMismatch)));
+    }
+
+    #[test]
+    fn unbound_ciphertexts_open_unless_strict() {
+        let key = data_key(false);
+        let v1 = unbound(&key, "secret");
+        let legacy = v1.strip_prefix("v1:dek:").unwrap().to_string();
+
+        assert_eq!(key.decrypt(b"any", &v1).unwrap(), "secret");
+        assert_eq!(key.decrypt(b"any", &legacy).unwrap(), "secret");
+
+        let strict = data_key(true);
+        assert!(matches!(strict.decrypt(b"any", &v1), Err(EncryptionError::UnboundRejected)));
+        assert!(matches!(strict.decrypt(b"any", &legacy), Err(EncryptionError::UnboundRejected)));
+    }
+
+    #[test]
+    fn data_keys_refuse_master_key_envelopes() {
+        let key = data_key(false);
+        assert!(matches!(key.decrypt(b"", "v1:default:abcd"), Err(EncryptionError::UnknownKey(id)) if id == "default"));
+    }
+
+    #[test]
+    fn tampered_ciphertexts_fail() {
+        let key = data_key(false);
+        let mut ciphertext = key.encrypt(b"aad", "secret").unwrap();
+        let last = ciphertext.pop().unwrap();
+        ciphertext.push(if last == '0' { '1' } else { '0' });
+
+        assert!(key.decrypt(b"aad", &ciphertext).is_err());
+        assert!(key.decrypt(b"aad", "v2:dek:zz").is_err());
+        assert!(key.decrypt(b"aad", "v2:dek:00").is_err());
+    }
+
+    #[test]
+    fn wrapped_keys_round_trip_and_inherit_strictness() {
+        let service = service(false);
+        let key = DataKey::generate(service.is_strict()).unwrap();
+        let wrapped = service.wrap_key(&key).unwrap();
+        assert!(wrapped.starts_with("v1:default:"));
+
+        let unwrapped = service.unwrap_key(&wrapped).unwrap();
+        assert_eq!(unwrapped.key, key.key);
+        assert!(!unwrapped.is_strict());
+        assert!(!DataKey::generate(service.is_strict()).unwrap().is_strict());
+        assert!(DataKey::generate(true).unwrap().is_strict());
+    }
+
+    #[test]
+    fn master_key_opens_legacy_and_versioned_content() {
+        let service = service(false);
+        let key = service.key(LEGACY_KEY_ID).unwrap();
+        let v1 = seal(key, LEGACY_KEY_ID, &service.rng, None, b"old entry").unwrap();
+        let legacy = v1.strip_prefix("v1:default:").unwrap();
+
+        assert_eq!(service.decrypt(&v1).unwrap(), "old entry");
+        assert_eq!(service.decrypt(legacy).unwrap(), "old entry");
+        assert!(matches!(service.decrypt("v1:retired:abcd"), Err(EncryptionError::UnknownKey(_))));
+    }
+
+    #[test]
+    fn rejects_bad_master_key_configs() {
+        assert!(EncryptionService::from_keys(&[], false).is_err());
+        assert!(EncryptionService::from_keys(&[("k".to_string(), "abcd".to_string())], false).is_err());
+        assert!(EncryptionService::from_keys(
+            &[("k".to_string(), KEY_HEX.to_string()), ("k".to_string(), KEY_HEX.to_string())],
+            false,
+        )
+        .is_err());
+        assert!(parse_master_keys(&["no-separator".to_string()]).is_err());
+    }
+}
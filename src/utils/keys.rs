@@ -0,0 +1,90 @@
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::utils::encryption::{get_encryption_service, DataKey, EncryptionError};
+
+/// How a user's journal content is protected.
+pub enum EntryCipher {
+    /// Encrypted by the server with the user's data key.
+    Server(DataKey),
+    /// Encrypted by the client; the server stores ciphertext it cannot read.
+    Client,
+}
+
+/// Work out how the user's entries are encrypted, loading their data key
+/// unless they use client-side encryption.
+pub async fn entry_cipher(db: &PgPool, user_id: Uuid) -> Result<EntryCipher, EncryptionError> {
+    let client_encrypted = sqlx::query_scalar::<_, bool>(
+        "SELECT client_encrypted FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_one(db)
+    .await?;
+
+    if client_encrypted {
+        return Ok(EntryCipher::Client);
+    }
+
+    Ok(EntryCipher::Server(user_data_key(db, user_id).await?))
+}
+
+/// Load the user's data key, creating it on first use.
+pub async fn user_data_key(db: &PgPool, user_id: Uuid) -> Result<DataKey, EncryptionError> {
+    let wrapped = sqlx::query_scalar::<_, String>(
+        "SELECT wrapped_key FROM user_keys WHERE user_id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+
+    match wrapped {
+        Some(wrapped) => get_encryption_service().unwrap_key(&wrapped),
+        None => create_user_key(db, user_id).await,
+    }
+}
+
+/// Generate and store a data key for the user.
+///
+/// Entries written before per-user keys existed stay under the master key,
+/// with plaintext titles and tags, until the re-encryption job re-seals them
+/// with the new data key; it reports any it cannot open. Until then
+/// [`open_content`] falls back to the master key for them.
+///
+/// [`open_content`]: crate::utils::sealing::open_content
+async fn create_user_key(db: &PgPool, user_id: Uuid) -> Result<DataKey, EncryptionError> {
+    let service = get_encryption_service();
+    let mut tx = db.begin().await?;
+
+    // Serialise key creation per user
+    sqlx::query("SELECT id FROM users WHERE id = $1 FOR UPDATE")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // Another request may have created the key while we waited for the lock
+    let existing = sqlx::query_scalar::<_, String>(
+        "SELECT wrapped_key FROM user_keys WHERE user_id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(wrapped) = existing {
+        tx.commit().await?;
+        return service.unwrap_key(&wrapped);
+    }
+
+    let data_key = DataKey::generate(service.is_strict())?;
+
+    sqlx::query("INSERT INTO user_keys (user_id, wrapped_key, created_at) VALUES ($1, $2, $3)")
+        .bind(user_id)
+        .bind(service.wrap_key(&data_key)?)
+        .bind(OffsetDateTime::now_utc())
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(data_key)
+}
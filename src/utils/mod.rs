@@ -1 +1,4 @@
-pub mod encryption; 
\ No newline at end of file
+pub mod encryption;
+pub mod keys;
+pub mod sealing;
+pub mod search;
\ No newline at end of file
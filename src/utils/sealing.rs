@@ -0,0 +1,174 @@
+use uuid::Uuid;
+
+use crate::utils::encryption::{
+    decrypt_text, encrypt_text, entry_aad, get_encryption_service, DataKey, EncryptionError,
+    BOUND_ENVELOPE_PREFIX,
+};
+
+/// Blind index purpose for entry tags.
+const TAG_INDEX_PURPOSE: &str = "journal_entries.tags";
+
+/// Associated data of the tag at `position`, so tags cannot be reordered or
+/// swapped between positions without detection.
+fn tag_aad(user_id: Uuid, entry_id: Uuid, position: usize) -> Vec<u8> {
+    entry_aad(user_id, entry_id, &format!("tags.{position}"))
+}
+
+/// Encrypted title and tags of an entry, plus the tag blind index.
+pub struct SealedMetadata {
+    pub title: String,
+    pub tags: Option<Vec<String>>,
+    pub tag_index: Option<Vec<String>>,
+}
+
+pub fn seal_content(
+    data_key: &DataKey,
+    user_id: Uuid,
+    entry_id: Uuid,
+    content: &str,
+) -> Result<String, EncryptionError> {
+    encrypt_text(data_key, &entry_aad(user_id, entry_id, "content"), content)
+}
+
+/// Decrypt an entry's content. Unbound content the data key cannot open was
+/// written under the master key before the user had a data key, and is
+/// opened with that until the re-encryption job re-seals it; a strict key
+/// refuses it.
+pub fn open_content(
+    data_key: &DataKey,
+    user_id: Uuid,
+    entry_id: Uuid,
+    content: &str,
+) -> Result<String, EncryptionError> {
+    match decrypt_text(data_key, &entry_aad(user_id, entry_id, "content"), content) {
+        Err(err) if !data_key.is_strict() && !content.starts_with(BOUND_ENVELOPE_PREFIX) => {
+            get_encryption_service().decrypt(content).map_err(|_| err)
+        }
+        result => result,
+    }
+}
+
+/// Encrypt an entry's title and each of its tags, and compute the tag blind index.
+pub fn seal_metadata(
+    data_key: &DataKey,
+    user_id: Uuid,
+    entry_id: Uuid,
+    title: &str,
+    tags: Option<&[String]>,
+) -> Result<SealedMetadata, EncryptionError> {
+    let title = encrypt_text(data_key, &entry_aad(user_id, entry_id, "title"), title)?;
+
+    let (tags, tag_index) = match tags {
+        Some(tags) => {
+            let sealed = tags
+                .iter()
+                .enumerate()
+                .map(|(position, tag)| encrypt_text(data_key, &tag_aad(user_id, entry_id, position), tag))
+                .collect::<Result<Vec<_>, _>>()?;
+            let index = tags
+                .iter()
+                .map(|tag| tag_blind_index(data_key, tag))
+                .collect::<Result<Vec<_>, _>>()?;
+            (Some(sealed), Some(index))
+        }
+        None => (None, None),
+    };
+
+    Ok(SealedMetadata { title, tags, tag_index })
+}
+
+pub fn open_title(
+    data_key: &DataKey,
+    user_id: Uuid,
+    entry_id: Uuid,
+    title: &str,
+) -> Result<String, EncryptionError> {
+    decrypt_text(data_key, &entry_aad(user_id, entry_id, "title"), title)
+}
+
+/// Decrypt an entry's tags. Tags sealed before `position_bound` existed
+/// share one associated data for the whole list, and are refused by a
+/// strict key.
+pub fn open_tags(
+    data_key: &DataKey,
+    user_id: Uuid,
+    entry_id: Uuid,
+    tags: &[String],
+    position_bound: bool,
+) -> Result<Vec<String>, EncryptionError> {
+    if position_bound {
+        return tags
+            .iter()
+            .enumerate()
+            .map(|(position, tag)| decrypt_text(data_key, &tag_aad(user_id, entry_id, position), tag))
+            .collect();
+    }
+
+    if data_key.is_strict() {
+        return Err(EncryptionError::UnboundRejected);
+    }
+    let tags_aad = entry_aad(user_id, entry_id, "tags");
+    tags.iter()
+        .map(|tag| decrypt_text(data_key, &tags_aad, tag))
+        .collect()
+}
+
+/// Blind index of a single tag, as stored in `journal_entries.tag_index`.
+pub fn tag_blind_index(data_key: &DataKey, tag: &str) -> Result<String, EncryptionError> {
+    data_key.blind_index(TAG_INDEX_PURPOSE, tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::encryption::EncryptionService;
+
+    fn data_key(strict: bool) -> DataKey {
+        let service = EncryptionService::from_keys(&[("k1".to_string(), "11".repeat(32))], strict).unwrap();
+        let wrapped = service.wrap_key(&DataKey::generate(strict).unwrap()).unwrap();
+        service.unwrap_key(&wrapped).unwrap()
+    }
+
+    fn tags() -> Vec<String> {
+        vec!["work".to_string(), "travel".to_string()]
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        let key = data_key(true);
+        let (user_id, entry_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let sealed = seal_metadata(&key, user_id, entry_id, "Title", Some(&tags())).unwrap();
+        let opened = open_tags(&key, user_id, entry_id, &sealed.tags.unwrap(), true).unwrap();
+        assert_eq!(opened, tags());
+    }
+
+    #[test]
+    fn reordered_tags_do_not_open() {
+        let key = data_key(false);
+        let (user_id, entry_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let mut sealed = seal_metadata(&key, user_id, entry_id, "Title", Some(&tags())).unwrap().tags.unwrap();
+        sealed.swap(0, 1);
+        assert!(open_tags(&key, user_id, entry_id, &sealed, true).is_err());
+    }
+
+    #[test]
+    fn tags_without_position_are_refused_when_strict() {
+        let (user_id, entry_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let shared_aad = entry_aad(user_id, entry_id, "tags");
+
+        let lenient = data_key(false);
+        let sealed: Vec<String> = tags()
+            .iter()
+            .map(|tag| encrypt_text(&lenient, &shared_aad, tag).unwrap())
+            .collect();
+        assert_eq!(open_tags(&lenient, user_id, entry_id, &sealed, false).unwrap(), tags());
+
+        let strict = data_key(true);
+        assert!(matches!(
+            open_tags(&strict, user_id, entry_id, &sealed, false),
+            Err(EncryptionError::UnboundRejected)
+        ));
+    }
+}
@@ -0,0 +1,266 @@
+use sqlx::{PgConnection, Postgres, QueryBuilder};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::utils::encryption::{decrypt_text, encrypt_text, entry_aad, DataKey, EncryptionError};
+
+/// Shortest prefix that gets its own index token.
+pub const MIN_PREFIX_LEN: usize = 3;
+
+/// Longest prefix that gets its own index token. Longer prefix queries are
+/// matched on their first `MAX_PREFIX_LEN` characters.
+pub const MAX_PREFIX_LEN: usize = 16;
+
+/// Words longer than this are not indexed.
+const MAX_TERM_LEN: usize = 64;
+
+const TERM_PURPOSE: &str = "search_index.term";
+const PREFIX_PURPOSE: &str = "search_index.prefix";
+
+/// Rows per INSERT, well below the Postgres bind parameter limit.
+const INSERT_CHUNK: usize = 1000;
+
+/// A lowercased word and its byte span in the source text.
+pub struct Word {
+    pub term: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Split text into lowercased alphanumeric words.
+pub fn tokenize(text: &str) -> Vec<Word> {
+    let mut words = Vec::new();
+    let mut start = None;
+
+    for (index, ch) in text.char_indices() {
+        match (ch.is_alphanumeric(), start) {
+            (true, None) => start = Some(index),
+            (false, Some(word_start)) => {
+                words.push(Word { term: text[word_start..index].to_lowercase(), start: word_start, end: index });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(word_start) = start {
+        words.push(Word { term: text[word_start..].to_lowercase(), start: word_start, end: text.len() });
+    }
+
+    words
+}
+
+/// Word position at which an entry's content starts. Title words come
+/// first; the gap keeps phrases from matching across title and content.
+pub fn content_offset(title: &str) -> u32 {
+    tokenize(title).len() as u32 + 1
+}
+
+pub fn term_hash(data_key: &DataKey, term: &str) -> Result<String, EncryptionError> {
+    data_key.blind_index(TERM_PURPOSE, term)
+}
+
+pub fn prefix_hash(data_key: &DataKey, prefix: &str) -> Result<String, EncryptionError> {
+    let prefix: String = prefix.chars().take(MAX_PREFIX_LEN).collect();
+    data_key.blind_index(PREFIX_PURPOSE, &prefix)
+}
+
+fn positions_aad(user_id: Uuid, entry_id: Uuid) -> Vec<u8> {
+    entry_aad(user_id, entry_id, "search_index")
+}
+
+/// Decrypt the word positions stored for one index row.
+pub fn open_positions(
+    data_key: &DataKey,
+    user_id: Uuid,
+    entry_id: Uuid,
+    positions: &str,
+) -> Result<Vec<u32>, EncryptionError> {
+    decrypt_text(data_key, &positions_aad(user_id, entry_id), positions)?
+        .split(',')
+        .map(|position| position.parse().map_err(|_| EncryptionError::DecryptionFailed))
+        .collect()
+}
+
+/// Replace the search index rows of an entry with tokens from its plaintext
+/// title and content.
+pub async fn index_entry(
+    conn: &mut PgConnection,
+    data_key: &DataKey,
+    user_id: Uuid,
+    entry_id: Uuid,
+    title: &str,
+    content: &str,
+) -> Result<(), EncryptionError> {
+    let offset = content_offset(title);
+    let words = tokenize(title)
+        .into_iter()
+        .enumerate()
+        .map(|(position, word)| (position as u32, word))
+        .chain(
+            tokenize(content)
+                .into_iter()
+                .enumerate()
+                .map(|(position, word)| (offset + position as u32, word)),
+        );
+
+    let mut tokens: HashMap<String, Vec<u32>> = HashMap::new();
+    for (position, word) in words {
+        let length = word.term.chars().count();
+        if length > MAX_TERM_LEN {
+            continue;
+        }
+
+        tokens.entry(term_hash(data_key, &word.term)?).or_default().push(position);
+
+        for prefix_len in MIN_PREFIX_LEN..=length.min(MAX_PREFIX_LEN) {
+            let prefix: String = word.term.chars().take(prefix_len).collect();
+            tokens.entry(prefix_hash(data_key, &prefix)?).or_default().push(position);
+        }
+    }
+
+    sqlx::query("DELETE FROM search_index WHERE entry_id = $1")
+        .bind(entry_id)
+        .execute(&mut *conn)
+        .await?;
+
+    let aad = positions_aad(user_id, entry_id);
+    let rows = tokens
+        .into_iter()
+        .map(|(token_hash, positions)| {
+            let positions = positions
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+            Ok((token_hash, encrypt_text(data_key, &aad, &positions)?))
+        })
+        .collect::<Result<Vec<_>, EncryptionError>>()?;
+
+    for chunk in rows.chunks(INSERT_CHUNK) {
+        let mut query = QueryBuilder::<Postgres>::new(
+            "INSERT INTO search_index (user_id, token_hash, entry_id, positions) "
+        );
+        query.push_values(chunk, |mut row, (token_hash, positions)| {
+            row.push_bind(user_id)
+                .push_bind(token_hash)
+                .push_bind(entry_id)
+                .push_bind(positions);
+        });
+        query.build().execute(&mut *conn).await?;
+    }
+
+    sqlx::query("UPDATE journal_entries SET search_indexed = TRUE WHERE id = $1")
+        .bind(entry_id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// One part of a search query. All clauses must match.
+#[derive(Debug, PartialEq)]
+pub enum Clause {
+    Term(String),
+    Prefix(String),
+    Phrase(Vec<String>),
+}
+
+/// Parse `morning "long walk" run*` into a term, a phrase and a prefix clause.
+pub fn parse_query(query: &str) -> Vec<Clause> {
+    let mut clauses = Vec::new();
+
+    for (index, part) in query.split('"').enumerate() {
+        // Odd-numbered parts were inside quotes
+        if index % 2 == 1 {
+            let words: Vec<String> = tokenize(part).into_iter().map(|word| word.term).collect();
+            match words.len() {
+                0 => {}
+                1 => clauses.extend(words.into_iter().map(Clause::Term)),
+                _ => clauses.push(Clause::Phrase(words)),
+            }
+            continue;
+        }
+
+        for raw in part.split_whitespace() {
+            let stem = raw.trim_end_matches('*');
+            let is_prefix = stem.len() < raw.len();
+            for word in tokenize(stem) {
+                // Prefixes too short to be indexed are searched as whole words
+                if is_prefix && word.end == stem.len() && word.term.chars().count() >= MIN_PREFIX_LEN {
+                    clauses.push(Clause::Prefix(word.term));
+                } else {
+                    clauses.push(Clause::Term(word.term));
+                }
+            }
+        }
+    }
+
+    clauses
+}
+
+/// Index tokens a clause is looked up by, in word order.
+pub fn clause_tokens(data_key: &DataKey, clause: &Clause) -> Result<Vec<String>, EncryptionError> {
+    match clause {
+        Clause::Term(term) => Ok(vec![term_hash(data_key, term)?]),
+        Clause::Prefix(prefix) => Ok(vec![prefix_hash(data_key, prefix)?]),
+        Clause::Phrase(words) => words.iter().map(|word| term_hash(data_key, word)).collect(),
+    }
+}
+
+/// Word positions of an index token in each entry that contains it.
+pub type Postings = HashMap<Uuid, Vec<u32>>;
+
+/// Entries in which `tokens` occur at consecutive positions, with the
+/// position of each such occurrence.
+pub fn match_sequence(tokens: &[String], postings: &HashMap<String, Postings>) -> Postings {
+    let Some((first, rest)) = tokens.split_first() else {
+        return Postings::new();
+    };
+    let Some(candidates) = postings.get(first) else {
+        return Postings::new();
+    };
+
+    candidates
+        .iter()
+        .filter_map(|(entry_id, starts)| {
+            let starts: Vec<u32> = starts
+                .iter()
+                .copied()
+                .filter(|start| {
+                    rest.iter().enumerate().all(|(index, token)| {
+                        postings
+                            .get(token)
+                            .and_then(|entries| entries.get(entry_id))
+                            .is_some_and(|positions| positions.contains(&(start + index as u32 + 1)))
+                    })
+                })
+                .collect();
+            (!starts.is_empty()).then_some((*entry_id, starts))
+        })
+        .collect()
+}
+
+const SNIPPET_WORDS_BEFORE: usize = 8;
+const SNIPPET_WORDS_AFTER: usize = 16;
+
+/// Excerpt of `content` around its word at index `word`.
+pub fn snippet(content: &str, word: usize) -> String {
+    let words = tokenize(content);
+    let Some(last) = words.len().checked_sub(1) else {
+        return String::new();
+    };
+
+    let word = word.min(last);
+    let first = word.saturating_sub(SNIPPET_WORDS_BEFORE);
+    let last_shown = (word + SNIPPET_WORDS_AFTER).min(last);
+
+    let mut excerpt = String::new();
+    if first > 0 {
+        excerpt.push('…');
+    }
+    excerpt.push_str(&content[words[first].start..words[last_shown].end]);
+    if last_shown < last {
+        excerpt.push('…');
+    }
+    excerpt
+}
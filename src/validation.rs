@@ -0,0 +1,465 @@
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request},
+    response::Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::db::models::{
+    CreateJournalEntry, CreateUser, EntryDocument, ForgotPasswordRequest, LoginUser,
+    ResetPasswordRequest, UpdateClientKeyMaterial, UpdateJournalEntry,
+};
+use crate::error::AppError;
+
+pub const MIN_USERNAME_LEN: usize = 3;
+pub const MAX_USERNAME_LEN: usize = 50;
+pub const MAX_EMAIL_LEN: usize = 254;
+pub const MIN_PASSWORD_LEN: usize = 12;
+pub const MAX_PASSWORD_LEN: usize = 128;
+pub const MAX_TITLE_LEN: usize = 255; // Characters, as in the original VARCHAR(255) column
+pub const MAX_TAGS: usize = 20;
+pub const MAX_TAG_LEN: usize = 50;
+pub const MAX_CONTENT_BYTES: usize = 100 * 1024;
+pub const MAX_CIPHERTEXT_BYTES: usize = 256 * 1024;
+pub const MAX_WRAPPED_KEY_BYTES: usize = 1024;
+pub const MAX_KDF_BYTES: usize = 4096;
+
+/// Passwords with fewer distinct characters than this are rejected as trivial.
+const MIN_PASSWORD_DISTINCT_CHARS: usize = 5;
+
+/// Messages for each invalid field, keyed by field name.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct ValidationErrors(BTreeMap<String, Vec<String>>);
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.entry(field.into()).or_default().push(message.into());
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+/// A request body whose fields can be checked before it is acted on.
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+/// `Json<T>` that also runs `T`'s validation rules. Malformed bodies and
+/// invalid fields are both rejected as problem+json.
+pub struct ValidatedJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(request, state).await?;
+        value.validate()?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+fn check_username(errors: &mut ValidationErrors, username: &str) {
+    let len = username.chars().count();
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&len) {
+        errors.add(
+            "username",
+            format!("must be {MIN_USERNAME_LEN} to {MAX_USERNAME_LEN} characters"),
+        );
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        errors.add("username", "may only contain letters, digits, `_`, `-` and `.`");
+    }
+}
+
+fn check_email(errors: &mut ValidationErrors, email: &str) {
+    if email.len() > MAX_EMAIL_LEN {
+        errors.add("email", format!("must be at most {MAX_EMAIL_LEN} characters"));
+    }
+
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && domain.split('.').all(|label| !label.is_empty())
+                && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        }
+        None => false,
+    };
+    if !valid {
+        errors.add("email", "must be a valid email address");
+    }
+}
+
+/// Only the limits of an email address given to look up an existing
+/// account, which may predate the current format rules.
+fn check_email_limits(errors: &mut ValidationErrors, email: &str) {
+    if email.is_empty() {
+        errors.add("email", "must not be empty");
+    }
+    if email.len() > MAX_EMAIL_LEN {
+        errors.add("email", format!("must be at most {MAX_EMAIL_LEN} characters"));
+    }
+}
+
+/// Password policy: length limits, no trivially repetitive passwords, and
+/// no passwords built around the account's own username or email.
+pub fn check_password(errors: &mut ValidationErrors, password: &str, identities: &[&str]) {
+    let len = password.chars().count();
+    if len < MIN_PASSWORD_LEN {
+        errors.add("password", format!("must be at least {MIN_PASSWORD_LEN} characters"));
+    }
+    if len > MAX_PASSWORD_LEN {
+        errors.add("password", format!("must be at most {MAX_PASSWORD_LEN} characters"));
+    }
+    if password.chars().collect::<HashSet<_>>().len() < MIN_PASSWORD_DISTINCT_CHARS {
+        errors.add("password", "is too simple");
+    }
+
+    let lowered = password.to_lowercase();
+    if identities
+        .iter()
+        .filter(|identity| identity.len() >= MIN_USERNAME_LEN)
+        .any(|identity| lowered.contains(&identity.to_lowercase()))
+    {
+        errors.add("password", "must not contain your username or email");
+    }
+}
+
+fn check_title(errors: &mut ValidationErrors, title: &str) {
+    if title.trim().is_empty() {
+        errors.add("title", "must not be empty");
+    }
+    if title.chars().count() > MAX_TITLE_LEN {
+        errors.add("title", format!("must be at most {MAX_TITLE_LEN} characters"));
+    }
+}
+
+fn check_content(errors: &mut ValidationErrors, content: Option<&str>, ciphertext: Option<&str>) {
+    if content.is_some_and(|content| content.len() > MAX_CONTENT_BYTES) {
+        errors.add("content", format!("must be at most {MAX_CONTENT_BYTES} bytes"));
+    }
+    if ciphertext.is_some_and(|ciphertext| ciphertext.len() > MAX_CIPHERTEXT_BYTES) {
+        errors.add("ciphertext", format!("must be at most {MAX_CIPHERTEXT_BYTES} bytes"));
+    }
+}
+
+/// Client key material as sent to `PUT /keys` or with a password reset;
+/// `field` prefixes the field names in errors.
+fn check_key_material(errors: &mut ValidationErrors, field: &str, material: &UpdateClientKeyMaterial) {
+    if material.wrapped_key.is_empty() {
+        errors.add(format!("{field}wrapped_key"), "must not be empty");
+    } else if material.wrapped_key.len() > MAX_WRAPPED_KEY_BYTES {
+        errors.add(
+            format!("{field}wrapped_key"),
+            format!("must be at most {MAX_WRAPPED_KEY_BYTES} bytes"),
+        );
+    }
+
+    if !material.kdf.is_object() {
+        errors.add(format!("{field}kdf"), "must be an object");
+    } else if material.kdf.to_string().len() > MAX_KDF_BYTES {
+        errors.add(format!("{field}kdf"), format!("must be at most {MAX_KDF_BYTES} bytes"));
+    }
+}
+
+fn check_mood_score(errors: &mut ValidationErrors, mood_score: Option<i32>) {
+    if mood_score.is_some_and(|score| !(1..=10).contains(&score)) {
+        errors.add("mood_score", "must be between 1 and 10");
+    }
+}
+
+fn check_tags(errors: &mut ValidationErrors, tags: Option<&[String]>) {
+    let Some(tags) = tags else {
+        return;
+    };
+
+    if tags.len() > MAX_TAGS {
+        errors.add("tags", format!("must have at most {MAX_TAGS} tags"));
+    }
+    for (i, tag) in tags.iter().enumerate() {
+        if tag.trim().is_empty() {
+            errors.add(format!("tags[{i}]"), "must not be empty");
+        } else if tag.chars().count() > MAX_TAG_LEN {
+            errors.add(format!("tags[{i}]"), format!("must be at most {MAX_TAG_LEN} characters"));
+        }
+    }
+}
+
+impl Validate for CreateUser {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_username(&mut errors, &self.username);
+        check_email(&mut errors, &self.email);
+
+        let local_part = self.email.split('@').next().unwrap_or_default();
+        check_password(&mut errors, &self.password, &[&self.username, local_part]);
+        errors.into_result()
+    }
+}
+
+impl Validate for LoginUser {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_email_limits(&mut errors, &self.email);
+        // Only the limits; the policy may have changed since the password was set
+        if self.password.is_empty() {
+            errors.add("password", "must not be empty");
+        }
+        if self.password.chars().count() > MAX_PASSWORD_LEN {
+            errors.add("password", format!("must be at most {MAX_PASSWORD_LEN} characters"));
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for ForgotPasswordRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_email_limits(&mut errors, &self.email);
+        errors.into_result()
+    }
+}
+
+impl Validate for ResetPasswordRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if self.token.is_empty() {
+            errors.add("token", "must not be empty");
+        }
+        // The username and email are checked once the token names the user
+        check_password(&mut errors, &self.password, &[]);
+        if let Some(material) = &self.key_material {
+            check_key_material(&mut errors, "key_material.", material);
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for UpdateClientKeyMaterial {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_key_material(&mut errors, "", self);
+        errors.into_result()
+    }
+}
+
+impl Validate for CreateJournalEntry {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_title(&mut errors, &self.title);
+        check_content(&mut errors, self.content.as_deref(), self.ciphertext.as_deref());
+        check_mood_score(&mut errors, self.mood_score);
+        check_tags(&mut errors, self.tags.as_deref());
+        errors.into_result()
+    }
+}
+
+impl Validate for UpdateJournalEntry {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(title) = &self.title {
+            check_title(&mut errors, title);
+        }
+        check_content(&mut errors, self.content.as_deref(), self.ciphertext.as_deref());
+        check_mood_score(&mut errors, self.mood_score);
+        check_tags(&mut errors, self.tags.as_deref());
+        errors.into_result()
+    }
+}
+
+impl Validate for EntryDocument {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_title(&mut errors, &self.title);
+        check_content(&mut errors, self.content.as_deref(), self.ciphertext.as_deref());
+        check_mood_score(&mut errors, self.mood_score);
+        check_tags(&mut errors, self.tags.as_deref());
+        errors.into_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fields with errors, as reported to the client.
+    fn invalid_fields(result: Result<(), ValidationErrors>) -> Vec<String> {
+        result.err().map(|errors| errors.0.into_keys().collect()).unwrap_or_default()
+    }
+
+    fn user(username: &str, email: &str, password: &str) -> CreateUser {
+        CreateUser {
+            username: username.to_string(),
+            email: email.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    fn entry(title: &str) -> CreateJournalEntry {
+        CreateJournalEntry {
+            title: title.to_string(),
+            content: Some("Went for a walk".to_string()),
+            ciphertext: None,
+            kdf: None,
+            mood_score: Some(6),
+            tags: Some(vec!["outdoors".to_string()]),
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_registration() {
+        assert!(user("jane_doe", "jane@example.com", "plum-Kettle-42-river").validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_usernames() {
+        assert_eq!(invalid_fields(user("jd", "jane@example.com", "plum-Kettle-42-river").validate()), ["username"]);
+        assert_eq!(invalid_fields(user("jane doe", "jane@example.com", "plum-Kettle-42-river").validate()), ["username"]);
+    }
+
+    #[test]
+    fn rejects_bad_emails() {
+        for email in ["jane", "@example.com", "jane@localhost", "jane@example..com", "jane@a@example.com", "ja ne@example.com"] {
+            assert_eq!(
+                invalid_fields(user("jane_doe", email, "plum-Kettle-42-river").validate()),
+                ["email"],
+                "{email}"
+            );
+        }
+    }
+
+    #[test]
+    fn password_policy() {
+        let check = |password: &str| {
+            let mut errors = ValidationErrors::default();
+            check_password(&mut errors, password, &["jane_doe", "jane"]);
+            errors.0.remove("password").unwrap_or_default()
+        };
+
+        assert!(check("plum-Kettle-42-river").is_empty());
+        assert_eq!(check("short-1A"), [format!("must be at least {MIN_PASSWORD_LEN} characters")]);
+        assert_eq!(check(&"ab".repeat(10)), ["is too simple"]);
+        assert_eq!(check("my-JANE_DOE-password"), ["must not contain your username or email"]);
+        assert!(check(&"x1Y2z".repeat(30)).contains(&format!("must be at most {MAX_PASSWORD_LEN} characters")));
+    }
+
+    #[test]
+    fn short_identities_do_not_restrict_passwords() {
+        let mut errors = ValidationErrors::default();
+        check_password(&mut errors, "plum-Kettle-42-river", &["pl"]);
+        assert!(errors.into_result().is_ok());
+    }
+
+    #[test]
+    fn login_only_checks_limits() {
+        let login = LoginUser {
+            email: "jane@example.com".to_string(),
+            password: "aaaa".to_string(),
+        };
+        assert!(login.validate().is_ok());
+
+        let legacy_email = LoginUser {
+            email: "jane@localhost".to_string(),
+            password: "secret".to_string(),
+        };
+        assert!(legacy_email.validate().is_ok());
+
+        let empty = LoginUser {
+            email: String::new(),
+            password: String::new(),
+        };
+        assert_eq!(invalid_fields(empty.validate()), ["email", "password"]);
+
+        let forgot = ForgotPasswordRequest { email: "x".repeat(MAX_EMAIL_LEN + 1) };
+        assert_eq!(invalid_fields(forgot.validate()), ["email"]);
+    }
+
+    #[test]
+    fn entry_rules() {
+        assert!(entry("Tuesday").validate().is_ok());
+        assert_eq!(invalid_fields(entry("   ").validate()), ["title"]);
+        assert_eq!(invalid_fields(entry(&"t".repeat(MAX_TITLE_LEN + 1)).validate()), ["title"]);
+
+        let mut moody = entry("Tuesday");
+        moody.mood_score = Some(11);
+        assert_eq!(invalid_fields(moody.validate()), ["mood_score"]);
+
+        let mut long = entry("Tuesday");
+        long.content = Some("x".repeat(MAX_CONTENT_BYTES + 1));
+        assert_eq!(invalid_fields(long.validate()), ["content"]);
+    }
+
+    #[test]
+    fn tag_errors_name_the_tag() {
+        let mut tagged = entry("Tuesday");
+        tagged.tags = Some(vec!["fine".to_string(), " ".to_string(), "t".repeat(MAX_TAG_LEN + 1)]);
+        assert_eq!(invalid_fields(tagged.validate()), ["tags[1]", "tags[2]"]);
+
+        tagged.tags = Some(vec!["tag".to_string(); MAX_TAGS + 1]);
+        assert_eq!(invalid_fields(tagged.validate()), ["tags"]);
+    }
+
+    #[test]
+    fn updates_only_check_given_fields() {
+        let update = UpdateJournalEntry {
+            title: None,
+            content: None,
+            ciphertext: None,
+            kdf: None,
+            mood_score: Some(3),
+            tags: None,
+            version: None,
+        };
+        assert!(update.validate().is_ok());
+    }
+
+    #[test]
+    fn reset_key_material_is_checked_like_put_keys() {
+        let material = |wrapped_key: &str, kdf: serde_json::Value| UpdateClientKeyMaterial {
+            wrapped_key: wrapped_key.to_string(),
+            kdf,
+        };
+        let reset = |key_material| ResetPasswordRequest {
+            token: "token".to_string(),
+            password: "Correct-Horse-9-battery".to_string(),
+            key_material,
+        };
+
+        let valid = material("d3JhcHBlZA", serde_json::json!({"alg": "argon2id"}));
+        assert!(valid.validate().is_ok());
+        assert!(reset(Some(valid)).validate().is_ok());
+
+        let bad = material("", serde_json::json!("argon2id"));
+        assert_eq!(invalid_fields(bad.validate()), ["kdf", "wrapped_key"]);
+        assert_eq!(
+            invalid_fields(reset(Some(bad)).validate()),
+            ["key_material.kdf", "key_material.wrapped_key"]
+        );
+
+        let oversized = material(
+            &"k".repeat(MAX_WRAPPED_KEY_BYTES + 1),
+            serde_json::json!({"salt": "s".repeat(MAX_KDF_BYTES)}),
+        );
+        assert_eq!(
+            invalid_fields(reset(Some(oversized)).validate()),
+            ["key_material.kdf", "key_material.wrapped_key"]
+        );
+    }
+}